@@ -3,7 +3,7 @@
 rm -f main main.ll
 export RUST_LOG=debug
 set -x
-cargo run --bin $1 
-clang -o main main.ll
+cargo run --bin $1 -- "${@:2}"
+clang -o main main.ll -lstdc++ -lm
 ./main
 echo $?
@@ -0,0 +1,112 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Lt,
+    Eq,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i64),
+    Variable(String),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Declare(String, Expr),
+    Assign(String, Expr),
+    Print(String, Vec<Expr>),
+    Block(Vec<Stmt>),
+    If {
+        cond: Expr,
+        then_body: Vec<Stmt>,
+        else_body: Vec<Stmt>,
+    },
+    While {
+        cond: Expr,
+        body: Vec<Stmt>,
+    },
+    Break,
+    Continue,
+    Return(Expr),
+    // Runs when the enclosing block is left
+    Defer(Box<Stmt>),
+}
+
+impl Stmt {
+    // A deferred statement must not leave the scope it runs in, a break or
+    // continue is only allowed inside a loop of its own
+    pub fn jumps_out(&self, in_loop: bool) -> bool {
+        match self {
+            Stmt::Return(_) => true,
+            Stmt::Break | Stmt::Continue => !in_loop,
+            Stmt::Block(stmts) => stmts.iter().any(|stmt| stmt.jumps_out(in_loop)),
+            Stmt::If {
+                then_body,
+                else_body,
+                ..
+            } => then_body
+                .iter()
+                .chain(else_body)
+                .any(|stmt| stmt.jumps_out(in_loop)),
+            Stmt::While { body, .. } => body.iter().any(|stmt| stmt.jumps_out(true)),
+            Stmt::Defer(stmt) => stmt.jumps_out(in_loop),
+            _ => false,
+        }
+    }
+
+    // Listing lines the statement takes, a block or body also takes the lines
+    // of its braces
+    pub fn listing_lines(&self) -> usize {
+        match self {
+            Stmt::Block(stmts) => listing_lines(stmts) + 2,
+            Stmt::If {
+                then_body,
+                else_body,
+                ..
+            } => {
+                let else_lines = match else_body.len() {
+                    0 => 0,
+                    _ => listing_lines(else_body) + 1,
+                };
+                listing_lines(then_body) + else_lines + 2
+            }
+            Stmt::While { body, .. } => listing_lines(body) + 2,
+            Stmt::Defer(stmt) => stmt.listing_lines(),
+            _ => 1,
+        }
+    }
+}
+
+pub fn listing_lines(stmts: &[Stmt]) -> usize {
+    stmts.iter().map(Stmt::listing_lines).sum()
+}
+
+pub fn num(value: i64) -> Expr {
+    Expr::Number(value)
+}
+
+pub fn var(name: &str) -> Expr {
+    Expr::Variable(name.to_string())
+}
+
+pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+}
+
+pub fn declare(name: &str, value: Expr) -> Stmt {
+    Stmt::Declare(name.to_string(), value)
+}
+
+pub fn assign(name: &str, value: Expr) -> Stmt {
+    Stmt::Assign(name.to_string(), value)
+}
+
+pub fn print(fmt: &str, args: Vec<Expr>) -> Stmt {
+    Stmt::Print(fmt.to_string(), args)
+}
+
+pub fn defer(stmt: Stmt) -> Stmt {
+    Stmt::Defer(Box::new(stmt))
+}
@@ -0,0 +1,386 @@
+use crate::ast::*;
+use crate::env::*;
+use crate::{Compiler, LoopContext};
+
+use inkwell::basic_block::BasicBlock;
+use inkwell::intrinsics::Intrinsic;
+use inkwell::types::IntType;
+use inkwell::values::{BasicMetadataValueEnum, FunctionValue, IntValue, PointerValue};
+use inkwell::IntPredicate;
+
+fn int_predicate(op: BinaryOp) -> Option<IntPredicate> {
+    match op {
+        BinaryOp::Lt => Some(IntPredicate::SLT),
+        BinaryOp::Eq => Some(IntPredicate::EQ),
+        BinaryOp::Add => None,
+    }
+}
+
+impl<'ctx> Compiler<'ctx> {
+    fn current_function(&self) -> FunctionValue<'ctx> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .unwrap()
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some()
+    }
+
+    fn branch_to(&self, block: BasicBlock<'ctx>) {
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(block);
+        }
+    }
+
+    //
+    // Every variable lives in the entry block, whichever scope declares it.
+    // An alloca inside a loop body runs on every iteration and keeps growing
+    // the stack, and mem2reg only promotes allocas of the entry block.
+    //
+    fn build_entry_alloca(&self, ty: IntType<'ctx>, name: &str) -> PointerValue<'ctx> {
+        let entry_block = self.current_function().get_first_basic_block().unwrap();
+        let builder = self.context.create_builder();
+        match entry_block.get_first_instruction() {
+            Some(instruction) => builder.position_before(&instruction),
+            None => builder.position_at_end(entry_block),
+        }
+        builder.build_alloca(ty, name)
+    }
+
+    fn llvm_type(&self, data_type: &DataType) -> IntType<'ctx> {
+        match data_type {
+            DataType::Number => self.i32_type,
+        }
+    }
+
+    //
+    // A variable is live from its declaration to the end of its scope:
+    //
+    //   {
+    //       int b = 20;    llvm.lifetime.start(4, b)
+    //       ...
+    //   }                  llvm.lifetime.end(4, b)
+    //
+    // so that variables of disjoint scopes can share a stack slot, and
+    // address sanitizer builds catch uses after the scope. Like clang, the
+    // start is at the declaration rather than at the entry of the scope: no
+    // statement before it can name the variable, so the slot is free until
+    // then.
+    //
+    fn build_lifetime(&self, intrinsic: &str, pointer: &Pointer<'ctx>) {
+        let intrinsic = Intrinsic::find(intrinsic).unwrap();
+        let func = intrinsic
+            .get_declaration(&self.module, &[pointer.ptr.get_type().into()])
+            .unwrap();
+        let size = self.llvm_type(&pointer.data_type).get_bit_width() / 8;
+        let size = self.context.i64_type().const_int(size as u64, false);
+        self.builder
+            .build_call(func, &[size.into(), pointer.ptr.into()], "");
+    }
+
+    fn end_lifetimes(&self, scope: ScopeId) {
+        let variables = self.scopes.borrow().variables(scope);
+        for pointer in variables.iter().rev() {
+            self.build_lifetime("llvm.lifetime.end", pointer);
+        }
+    }
+
+    pub fn compile_program(&self, stmts: &[Stmt]) -> Result<(), String> {
+        let entry_block = self.context.append_basic_block(self.main_func, "entry");
+        self.builder.position_at_end(entry_block);
+
+        // int main() { is line 1, the closing brace the last one
+        let end = listing_lines(stmts) + 2;
+        let root = self.scopes.borrow_mut().add_scope(None, 1, end);
+        self.compile_block(root, stmts, 2)?;
+        if !self.is_terminated() {
+            self.builder
+                .build_return(Some(&self.i32_type.const_int(0, false)));
+        }
+        Ok(())
+    }
+
+    // The variable name refers to, recorded as used on the current line
+    fn lookup(&self, scope: ScopeId, name: &str) -> Result<Pointer<'ctx>, String> {
+        let mut scopes = self.scopes.borrow_mut();
+        let id = scopes.lookup(scope, name)?;
+        scopes.add_use(id, self.line.get());
+        Ok(scopes.symbol(id).pointer.clone())
+    }
+
+    fn compile_expr(&self, scope: ScopeId, expr: &Expr) -> Result<IntValue<'ctx>, String> {
+        match expr {
+            Expr::Number(value) => Ok(self.i32_type.const_int(*value as u64, true)),
+            Expr::Variable(name) => {
+                let pointer = self.lookup(scope, name)?;
+                Ok(self
+                    .builder
+                    .build_load(self.llvm_type(&pointer.data_type), pointer.ptr, name)
+                    .into_int_value())
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.compile_expr(scope, lhs)?;
+                let rhs = self.compile_expr(scope, rhs)?;
+                Ok(match op {
+                    BinaryOp::Add => self.builder.build_int_add(lhs, rhs, "add"),
+                    _ => {
+                        let predicate = int_predicate(*op).unwrap();
+                        let cmp = self.builder.build_int_compare(predicate, lhs, rhs, "cmp");
+                        self.builder.build_int_z_extend(cmp, self.i32_type, "cmp")
+                    }
+                })
+            }
+        }
+    }
+
+    // Comparisons are branched on directly, anything else is true when non-zero
+    fn compile_cond(&self, scope: ScopeId, expr: &Expr) -> Result<IntValue<'ctx>, String> {
+        if let Expr::Binary(op, lhs, rhs) = expr {
+            if let Some(predicate) = int_predicate(*op) {
+                let lhs = self.compile_expr(scope, lhs)?;
+                let rhs = self.compile_expr(scope, rhs)?;
+                return Ok(self.builder.build_int_compare(predicate, lhs, rhs, "cond"));
+            }
+        }
+        let value = self.compile_expr(scope, expr)?;
+        Ok(self.builder.build_int_compare(
+            IntPredicate::NE,
+            value,
+            self.i32_type.const_zero(),
+            "cond",
+        ))
+    }
+
+    //
+    // Every block gets its own scope so that declarations do not leak out of
+    // it. Statements following a break, continue or return are unreachable
+    // and skipped, otherwise the block falls through, runs its deferred
+    // statements and ends the lifetimes of its variables. The statements
+    // start at the listing line first_line, each taking its listing lines.
+    //
+    fn compile_block(
+        &self,
+        parent: ScopeId,
+        stmts: &[Stmt],
+        first_line: ListingLine,
+    ) -> Result<(), String> {
+        let end = first_line + listing_lines(stmts) - 1;
+        let scope = self
+            .scopes
+            .borrow_mut()
+            .add_scope(Some(parent), first_line, end);
+        let mut line = first_line;
+        for stmt in stmts {
+            if self.is_terminated() {
+                break;
+            }
+            self.line.set(line);
+            self.compile_stmt(scope, stmt)?;
+            line += stmt.listing_lines();
+        }
+        if !self.is_terminated() {
+            self.compile_defers(scope)?;
+            self.end_lifetimes(scope);
+        }
+        Ok(())
+    }
+
+    //
+    // Deferred statements run last in first out. Each one is compiled as a
+    // block of its own, where it sees the variables of the scope it was
+    // deferred in with their values at the time the scope is left.
+    //
+    fn compile_defers(&self, scope: ScopeId) -> Result<(), String> {
+        let defers = self.scopes.borrow().defers(scope);
+        for (line, stmt) in defers.iter().rev() {
+            self.compile_block(scope, std::slice::from_ref(stmt), *line)?;
+        }
+        Ok(())
+    }
+
+    // Runs the deferred statements of scope and its parents up to, but not
+    // including, the given scope, and ends the lifetimes of their variables.
+    // None leaves every scope of the function.
+    fn exit_scopes(&self, scope: ScopeId, until: Option<ScopeId>) -> Result<(), String> {
+        let mut scope = Some(scope);
+        while let Some(current) = scope {
+            if until == Some(current) {
+                break;
+            }
+            self.compile_defers(current)?;
+            self.end_lifetimes(current);
+            scope = self.scopes.borrow().parent(current);
+        }
+        Ok(())
+    }
+
+    fn find_loop(&self) -> Result<LoopContext<'ctx>, String> {
+        self.loops
+            .borrow()
+            .last()
+            .cloned()
+            .ok_or_else(|| "break or continue outside of a loop".to_string())
+    }
+
+    fn compile_stmt(&self, scope: ScopeId, stmt: &Stmt) -> Result<(), String> {
+        let line = self.line.get();
+        match stmt {
+            Stmt::Declare(name, value) => {
+                let declared = self.scopes.borrow().declared_on(scope, name, line);
+                if declared.is_none() && self.scopes.borrow().shadows(scope, name) {
+                    let message = format!("{name} shadows a variable of an outer scope");
+                    match self.shadowing {
+                        Shadowing::Allow => {}
+                        Shadowing::Warn => self.warnings.borrow_mut().push(message),
+                        Shadowing::Deny => return Err(message),
+                    }
+                }
+                let value = self.compile_expr(scope, value)?;
+                let pointer = match declared {
+                    Some(id) => self.scopes.borrow().symbol(id).pointer.clone(),
+                    None => Pointer {
+                        ptr: self.build_entry_alloca(self.i32_type, name),
+                        data_type: DataType::Number,
+                    },
+                };
+                self.build_lifetime("llvm.lifetime.start", &pointer);
+                self.builder.build_store(pointer.ptr, value);
+                if declared.is_none() {
+                    self.scopes.borrow_mut().add(scope, name, line, pointer)?;
+                }
+            }
+            Stmt::Assign(name, value) => {
+                let value = self.compile_expr(scope, value)?;
+                let ptr = self.lookup(scope, name)?.ptr;
+                self.builder.build_store(ptr, value);
+            }
+            Stmt::Print(fmt, args) => {
+                let fmt_str = unsafe { self.builder.build_global_string(fmt, "fmt_str") };
+                let mut call_args: Vec<BasicMetadataValueEnum> =
+                    vec![fmt_str.as_pointer_value().into()];
+                for arg in args {
+                    call_args.push(self.compile_expr(scope, arg)?.into());
+                }
+                self.builder
+                    .build_call(self.printf_func, &call_args, "printf");
+            }
+            Stmt::Block(stmts) => self.compile_block(scope, stmts, line + 1)?,
+            Stmt::If {
+                cond,
+                then_body,
+                else_body,
+            } => self.compile_if(scope, line, cond, then_body, else_body)?,
+            Stmt::While { cond, body } => self.compile_while(scope, line, cond, body)?,
+            Stmt::Break => {
+                let context = self.find_loop()?;
+                self.exit_scopes(scope, Some(context.scope))?;
+                self.builder.build_unconditional_branch(context.break_block);
+            }
+            Stmt::Continue => {
+                let context = self.find_loop()?;
+                self.exit_scopes(scope, Some(context.scope))?;
+                self.builder
+                    .build_unconditional_branch(context.continue_block);
+            }
+            // The value is computed before the deferred statements run
+            Stmt::Return(value) => {
+                let value = self.compile_expr(scope, value)?;
+                self.exit_scopes(scope, None)?;
+                self.builder.build_return(Some(&value));
+            }
+            Stmt::Defer(deferred) => {
+                if deferred.jumps_out(false) {
+                    return Err(format!(
+                        "deferred statement cannot leave its scope: {deferred:?}"
+                    ));
+                }
+                self.scopes.borrow_mut().defer(scope, line, deferred);
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_if(
+        &self,
+        scope: ScopeId,
+        line: ListingLine,
+        cond: &Expr,
+        then_body: &[Stmt],
+        else_body: &[Stmt],
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let comparison = self.compile_cond(scope, cond)?;
+
+        let if_true_block = self.context.append_basic_block(func, "if_true");
+        let if_false_block = self.context.append_basic_block(func, "if_false");
+        let merge_block = self.context.append_basic_block(func, "merge");
+        self.builder
+            .build_conditional_branch(comparison, if_true_block, if_false_block);
+
+        // Generate code for if true block
+        self.builder.position_at_end(if_true_block);
+        self.compile_block(scope, then_body, line + 1)?;
+        self.branch_to(merge_block);
+
+        // Generate code for if false block
+        if_false_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(if_false_block);
+        // The else body starts past the then body and } else {
+        self.compile_block(scope, else_body, line + listing_lines(then_body) + 2)?;
+        self.branch_to(merge_block);
+
+        // Generate code for merge block
+        merge_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(merge_block);
+        Ok(())
+    }
+
+    fn compile_while(
+        &self,
+        scope: ScopeId,
+        line: ListingLine,
+        cond: &Expr,
+        body: &[Stmt],
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let cond_block = self.context.append_basic_block(func, "cond");
+        let body_block = self.context.append_basic_block(func, "body");
+        let loop_end_block = self.context.append_basic_block(func, "loop_end");
+
+        self.builder.build_unconditional_branch(cond_block);
+
+        self.builder.position_at_end(cond_block);
+        let comparison = self.compile_cond(scope, cond)?;
+        self.builder
+            .build_conditional_branch(comparison, body_block, loop_end_block);
+
+        // Generate code for body block, break and continue leave the scopes
+        // of the body but not scope
+        self.builder.position_at_end(body_block);
+        self.loops.borrow_mut().push(LoopContext {
+            scope,
+            continue_block: cond_block,
+            break_block: loop_end_block,
+        });
+        let compiled = self.compile_block(scope, body, line + 1);
+        self.loops.borrow_mut().pop();
+        compiled?;
+        self.branch_to(cond_block);
+
+        // Generate code for loop end block
+        loop_end_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(loop_end_block);
+        Ok(())
+    }
+}
@@ -1,40 +1,273 @@
+use crate::ast::Stmt;
 use inkwell::values::PointerValue;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DataType {
     Number,
 }
 
+// What declaring a variable that hides one of an outer scope does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shadowing {
+    Allow,
+    Warn,
+    Deny,
+}
+
 #[derive(Debug, Clone)]
 pub struct Pointer<'ctx> {
     pub ptr: PointerValue<'ctx>,
     pub data_type: DataType,
 }
 
-pub struct Env<'ctx> {
-    parent: Option<Rc<RefCell<Env<'ctx>>>>,
-    symbols: HashMap<String, Pointer<'ctx>>,
+// Index of a scope in the ScopeTree, stable for the lifetime of the tree
+pub type ScopeId = usize;
+// Index of a symbol in the ScopeTree
+pub type SymbolId = usize;
+
+//
+// A synthetic position, not a line of any source file: the AST carries no
+// spans. Statements are numbered the way the C listing in the comment above
+// each program lays them out, one statement per line and every brace on a
+// line of its own:
+//
+//   1  int main() {
+//   2      int count = 1;
+//   3      {
+//   4          int count = 2;
+//   5          printf("%d\n", count);
+//   6      }
+//   7      return count;
+//   8  }
+//
+pub type ListingLine = usize;
+
+// A declared variable, with the listing lines it is declared and used on
+#[derive(Debug, Clone)]
+pub struct Symbol<'ctx> {
+    pub name: String,
+    pub line: ListingLine,
+    pub pointer: Pointer<'ctx>,
+    // Listing lines reading or assigning the variable, in order
+    pub uses: Vec<ListingLine>,
+}
+
+pub struct Scope {
+    parent: Option<ScopeId>,
+    // Listing lines of the statements of the scope, empty for an empty block
+    start: ListingLine,
+    end: ListingLine,
+    symbols: HashMap<String, SymbolId>,
+    // The symbols in the order they were declared
+    declared: Vec<SymbolId>,
+    // Statements to run when the scope is left, in the order they were
+    // deferred, with the line they were deferred on
+    defers: Vec<(ListingLine, Stmt)>,
+}
+
+//
+// Every scope of the program, parents before their children. The tree is
+// kept after compilation, so that tools can ask what is visible at a listing
+// line or where a variable is used. In the listing above ListingLine, only
+// the inner count is visible at line 5, the outer one is used on line 7.
+//
+// A deferred statement is compiled again for every exit of its scope. Each
+// compilation finds the scopes, variables and deferred statements of the
+// previous one by their lines, so the tree holds them once.
+//
+#[derive(Default)]
+pub struct ScopeTree<'ctx> {
+    scopes: Vec<Scope>,
+    symbols: Vec<Symbol<'ctx>>,
 }
 
-impl<'ctx> Env<'ctx> {
-    pub fn new(parent: Option<Rc<RefCell<Env<'ctx>>>>) -> Self {
-        Self {
+impl<'ctx> ScopeTree<'ctx> {
+    pub fn add_scope(
+        &mut self,
+        parent: Option<ScopeId>,
+        start: ListingLine,
+        end: ListingLine,
+    ) -> ScopeId {
+        let existing = self
+            .scopes
+            .iter()
+            .position(|scope| scope.parent == parent && scope.start == start && scope.end == end);
+        if let Some(id) = existing {
+            return id;
+        }
+        self.scopes.push(Scope {
             parent,
+            start,
+            end,
             symbols: HashMap::new(),
+            declared: Vec::new(),
+            defers: Vec::new(),
+        });
+        self.scopes.len() - 1
+    }
+
+    pub fn add(
+        &mut self,
+        scope: ScopeId,
+        name: &str,
+        line: ListingLine,
+        pointer: Pointer<'ctx>,
+    ) -> Result<SymbolId, String> {
+        if self.scopes[scope].symbols.contains_key(name) {
+            return Err(format!("redeclaration of {name} in the same scope"));
         }
+        let id = self.symbols.len();
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            line,
+            pointer,
+            uses: Vec::new(),
+        });
+        self.scopes[scope].symbols.insert(name.to_string(), id);
+        self.scopes[scope].declared.push(id);
+        Ok(id)
     }
 
-    pub fn add(&mut self, name: &str, ptr: Pointer<'ctx>) {
-        self.symbols.insert(name.to_string(), ptr);
+    pub fn symbol(&self, id: SymbolId) -> &Symbol<'ctx> {
+        &self.symbols[id]
     }
 
-    pub fn get(&self, name: &str) -> Option<Pointer<'ctx>> {
-        match self.symbols.get(name) {
-            Some(value) => Some(value.clone()),
-            None => self.parent.as_ref().and_then(|o| o.borrow().get(name)),
+    // The variable a deferred statement compiled before declared on line
+    pub fn declared_on(&self, scope: ScopeId, name: &str, line: ListingLine) -> Option<SymbolId> {
+        self.scopes[scope]
+            .symbols
+            .get(name)
+            .copied()
+            .filter(|id| self.symbols[*id].line == line)
+    }
+
+    // Deferred statements are compiled once for every exit of their scope,
+    // a use is recorded once
+    pub fn add_use(&mut self, id: SymbolId, line: ListingLine) {
+        let uses = &mut self.symbols[id].uses;
+        if !uses.contains(&line) {
+            uses.push(line);
+            uses.sort();
+        }
+    }
+
+    // Whether declaring name in scope would hide a variable of an outer scope
+    pub fn shadows(&self, scope: ScopeId, name: &str) -> bool {
+        self.scopes[scope]
+            .parent
+            .is_some_and(|parent| self.get(parent, name).is_some())
+    }
+
+    pub fn parent(&self, scope: ScopeId) -> Option<ScopeId> {
+        self.scopes[scope].parent
+    }
+
+    pub fn defer(&mut self, scope: ScopeId, line: ListingLine, stmt: &Stmt) {
+        let defers = &mut self.scopes[scope].defers;
+        if defers.iter().all(|(deferred_on, _)| *deferred_on != line) {
+            defers.push((line, stmt.clone()));
+        }
+    }
+
+    pub fn defers(&self, scope: ScopeId) -> Vec<(ListingLine, Stmt)> {
+        self.scopes[scope].defers.clone()
+    }
+
+    // Variables declared in this scope alone, in declaration order
+    pub fn variables(&self, scope: ScopeId) -> Vec<Pointer<'ctx>> {
+        self.scopes[scope]
+            .declared
+            .iter()
+            .map(|id| self.symbols[*id].pointer.clone())
+            .collect()
+    }
+
+    pub fn get(&self, scope: ScopeId, name: &str) -> Option<SymbolId> {
+        let scope = &self.scopes[scope];
+        match scope.symbols.get(name) {
+            Some(id) => Some(*id),
+            None => scope.parent.and_then(|parent| self.get(parent, name)),
+        }
+    }
+
+    // Like get, but an undefined name is an error suggesting the closest
+    // visible one
+    pub fn lookup(&self, scope: ScopeId, name: &str) -> Result<SymbolId, String> {
+        self.get(scope, name)
+            .ok_or_else(|| match self.suggest(scope, name) {
+                Some(suggestion) => {
+                    format!("undefined variable {name}, did you mean {suggestion}?")
+                }
+                None => format!("undefined variable {name}"),
+            })
+    }
+
+    // Names of this and every enclosing scope
+    fn visible_names(&self, scope: ScopeId) -> Vec<String> {
+        let mut names: Vec<String> = self.scopes[scope].symbols.keys().cloned().collect();
+        if let Some(parent) = self.scopes[scope].parent {
+            names.extend(self.visible_names(parent));
+        }
+        names
+    }
+
+    // The visible name closest to name, if it is a likely typo: at most one
+    // edit for short names, a third of the length for longer ones
+    fn suggest(&self, scope: ScopeId, name: &str) -> Option<String> {
+        let max_distance = (name.len() / 3).max(1);
+        let mut names = self.visible_names(scope);
+        names.sort();
+        names
+            .into_iter()
+            .map(|candidate| (edit_distance(name, &candidate), candidate))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate)
+    }
+
+    // Variables a statement on line can refer to, innermost first. Those
+    // declared on the line itself are not visible to its initializer yet.
+    pub fn visible_at(&self, line: ListingLine) -> Vec<&Symbol<'ctx>> {
+        let innermost = (0..self.scopes.len())
+            .filter(|id| self.scopes[*id].start <= line && line <= self.scopes[*id].end)
+            .min_by_key(|id| self.scopes[*id].end - self.scopes[*id].start);
+
+        let mut visible = Vec::new();
+        let mut names = HashSet::new();
+        let mut scope = innermost;
+        while let Some(id) = scope {
+            for symbol in self.scopes[id].declared.iter().map(|id| &self.symbols[*id]) {
+                if symbol.line < line && names.insert(symbol.name.as_str()) {
+                    visible.push(symbol);
+                }
+            }
+            scope = self.scopes[id].parent;
+        }
+        visible
+    }
+
+    // Every variable named name
+    pub fn symbols_named(&self, name: &str) -> Vec<&Symbol<'ctx>> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.name == name)
+            .collect()
+    }
+}
+
+// Levenshtein distance, the number of inserted, removed or replaced
+// characters turning a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a_char != *b_char);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
         }
+        previous = current;
     }
+    previous[b.len()]
 }
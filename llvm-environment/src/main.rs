@@ -1,18 +1,29 @@
+mod ast;
+mod codegen;
 mod env;
+use crate::ast::*;
 use crate::env::*;
 use std::path::Path;
 
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::module::Module;
 use inkwell::types::IntType;
 use inkwell::values::FunctionValue;
 use inkwell::AddressSpace;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::cell::{Cell, RefCell};
 
 const MAIN_FUNC_NAME: &str = "main";
 
+// Branch targets of an enclosing loop and the scope it was entered from
+#[derive(Clone)]
+pub struct LoopContext<'ctx> {
+    pub scope: ScopeId,
+    pub continue_block: BasicBlock<'ctx>,
+    pub break_block: BasicBlock<'ctx>,
+}
+
 pub struct Compiler<'ctx> {
     pub context: &'ctx Context,
     pub builder: Builder<'ctx>,
@@ -21,6 +32,16 @@ pub struct Compiler<'ctx> {
 
     pub main_func: FunctionValue<'ctx>,
     pub printf_func: FunctionValue<'ctx>,
+
+    pub loops: RefCell<Vec<LoopContext<'ctx>>>,
+
+    pub shadowing: Shadowing,
+    pub warnings: RefCell<Vec<String>>,
+
+    // Every scope of the program, kept for queries after compilation
+    pub scopes: RefCell<ScopeTree<'ctx>>,
+    // Listing line of the statement being compiled
+    pub line: Cell<ListingLine>,
 }
 
 impl<'ctx> Compiler<'ctx> {
@@ -42,6 +63,11 @@ impl<'ctx> Compiler<'ctx> {
             i32_type,
             main_func,
             printf_func,
+            loops: RefCell::new(Vec::new()),
+            shadowing: Shadowing::Warn,
+            warnings: RefCell::new(Vec::new()),
+            scopes: RefCell::new(ScopeTree::default()),
+            line: Cell::new(1),
         }
     }
 }
@@ -52,62 +78,588 @@ impl<'ctx> Compiler<'ctx> {
 //
 //  {
 //      int b = 20;
-//      c = a + b;
+//      int c = a + b;
 //      printf("%d\n", c);
 //  }
 //  return 0;
 // }
 
-fn main() {
-    let context = Context::create();
-    let compiler = Compiler::new(&context);
-    let main_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "entry");
-    compiler.builder.position_at_end(main_block);
-    let global_env = Rc::new(RefCell::new(Env::new(None)));
-
-    let ptr = compiler.builder.build_alloca(compiler.i32_type, "a");
-    compiler
-        .builder
-        .build_store(ptr, compiler.i32_type.const_int(10, false));
-    global_env.borrow_mut().add(
-        "a",
-        Pointer {
-            ptr,
-            data_type: DataType::Number,
+fn program() -> Vec<Stmt> {
+    vec![
+        declare("a", num(10)),
+        Stmt::Block(vec![
+            declare("b", num(20)),
+            declare("c", binary(BinaryOp::Add, var("a"), var("b"))),
+            print("%d\n", vec![var("c")]),
+        ]),
+        Stmt::Return(num(0)),
+    ]
+}
+
+//
+// int main() {
+//     defer printf("main done\n");
+//     int i = 0;
+//     while (i < 3) {
+//         defer printf("end of iteration %d\n", i);
+//         i = i + 1;
+//         if (i == 2) {
+//             continue;
+//         }
+//         if (i == 3) {
+//             break;
+//         }
+//         printf("body %d\n", i);
+//     }
+//     {
+//         defer printf("leaving block\n");
+//         defer printf("deferred last runs first\n");
+//         printf("in block\n");
+//     }
+//     return i;
+// }
+//
+// Prints "body 1" and "end of iteration 1" to "end of iteration 3", the
+// continue and break leave the loop body through its deferred statement.
+// Then "in block", "deferred last runs first", "leaving block" and
+// "main done", and exits with 3.
+//
+
+fn defer_program() -> Vec<Stmt> {
+    vec![
+        defer(print("main done\n", vec![])),
+        declare("i", num(0)),
+        Stmt::While {
+            cond: binary(BinaryOp::Lt, var("i"), num(3)),
+            body: vec![
+                defer(print("end of iteration %d\n", vec![var("i")])),
+                assign("i", binary(BinaryOp::Add, var("i"), num(1))),
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("i"), num(2)),
+                    then_body: vec![Stmt::Continue],
+                    else_body: vec![],
+                },
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("i"), num(3)),
+                    then_body: vec![Stmt::Break],
+                    else_body: vec![],
+                },
+                print("body %d\n", vec![var("i")]),
+            ],
         },
-    );
-
-    let env = Rc::new(RefCell::new(Env::new(Some(global_env.clone()))));
-    let ptr = compiler.builder.build_alloca(compiler.i32_type, "b");
-    compiler
-        .builder
-        .build_store(ptr, compiler.i32_type.const_int(20, false));
-    env.borrow_mut().add(
-        "b",
-        Pointer {
-            ptr,
-            data_type: DataType::Number,
+        Stmt::Block(vec![
+            defer(print("leaving block\n", vec![])),
+            defer(print("deferred last runs first\n", vec![])),
+            print("in block\n", vec![]),
+        ]),
+        Stmt::Return(var("i")),
+    ]
+}
+
+//
+// int main() {
+//     int a = 1;
+//     int a = 2;
+//     return a;
+// }
+//
+// Fails to compile, a is declared twice in the same scope
+//
+
+fn redeclare_program() -> Vec<Stmt> {
+    vec![
+        declare("a", num(1)),
+        declare("a", num(2)),
+        Stmt::Return(var("a")),
+    ]
+}
+
+//
+// int main() {
+//     int count = 1;
+//     {
+//         int count = 2;
+//         printf("%d\n", count);
+//     }
+//     return count;
+// }
+//
+// Warns that the inner count shadows the outer one, prints 2 and exits 1.
+// With --shadowing=deny it fails to compile, --shadowing=allow silences it.
+// --visible-at=5 lists the inner count alone, --uses=count both counts and
+// the lines they are used on, 5 and 7.
+//
+
+fn shadow_program() -> Vec<Stmt> {
+    vec![
+        declare("count", num(1)),
+        Stmt::Block(vec![
+            declare("count", num(2)),
+            print("%d\n", vec![var("count")]),
+        ]),
+        Stmt::Return(var("count")),
+    ]
+}
+
+//
+// int main() {
+//     int total = 5;
+//     {
+//         int step = 1;
+//         printf("%d\n", totl + step);
+//     }
+//     return 0;
+// }
+//
+// Fails to compile, totl is undefined, did you mean total?
+//
+
+fn typo_program() -> Vec<Stmt> {
+    vec![
+        declare("total", num(5)),
+        Stmt::Block(vec![
+            declare("step", num(1)),
+            print(
+                "%d\n",
+                vec![binary(BinaryOp::Add, var("totl"), var("step"))],
+            ),
+        ]),
+        Stmt::Return(num(0)),
+    ]
+}
+
+//
+// int main() {
+//     int i = 0;
+//     int total = 0;
+//     while (i < 1000000) {
+//         int step = 1;
+//         total = total + step;
+//         i = i + step;
+//     }
+//     printf("%d\n", total);
+//     return 0;
+// }
+//
+// Prints 1000000. step is allocated once in the entry block, an alloca in
+// the loop body would take another slot of stack on each of the million
+// iterations and overflow it.
+//
+
+fn loop_locals_program() -> Vec<Stmt> {
+    vec![
+        declare("i", num(0)),
+        declare("total", num(0)),
+        Stmt::While {
+            cond: binary(BinaryOp::Lt, var("i"), num(1000000)),
+            body: vec![
+                declare("step", num(1)),
+                assign("total", binary(BinaryOp::Add, var("total"), var("step"))),
+                assign("i", binary(BinaryOp::Add, var("i"), var("step"))),
+            ],
         },
-    );
-
-    let a_ptr = env.borrow().get("a").unwrap().ptr;
-    let lhs = compiler.builder.build_load(compiler.i32_type, a_ptr, "a");
-
-    let b_ptr = env.borrow().get("b").unwrap().ptr;
-    let rhs = compiler.builder.build_load(compiler.i32_type, b_ptr, "b");
-
-    let c = compiler
-        .builder
-        .build_int_add(lhs.into_int_value(), rhs.into_int_value(), "c");
-    let int_fmt_str = unsafe { compiler.builder.build_global_string("%d\n", "int_fmt_str") };
-    compiler.builder.build_call(
-        compiler.printf_func,
-        &[int_fmt_str.as_pointer_value().into(), c.into()],
-        "printf",
-    );
-    let ret_val = compiler.i32_type.const_int(0, false);
-    compiler.builder.build_return(Some(&ret_val));
+        print("%d\n", vec![var("total")]),
+        Stmt::Return(num(0)),
+    ]
+}
+
+//
+// int main() {
+//     {
+//         int a = 1;
+//         printf("%d\n", a);
+//     }
+//     {
+//         int b = 2;
+//         printf("%d\n", b);
+//     }
+//     return 0;
+// }
+//
+// Prints 1 and 2. The lifetime of a ends before the one of b starts, so
+// the optimizer may keep both in the same stack slot.
+//
+
+fn sibling_scopes_program() -> Vec<Stmt> {
+    vec![
+        Stmt::Block(vec![declare("a", num(1)), print("%d\n", vec![var("a")])]),
+        Stmt::Block(vec![declare("b", num(2)), print("%d\n", vec![var("b")])]),
+        Stmt::Return(num(0)),
+    ]
+}
+
+// Usage: cargo run --bin llvm-environment [defer | redeclare | shadow | typo |
+//                                          loop_locals | sibling_scopes]
+//                                         [--shadowing=allow|warn|deny]
+//                                         [--visible-at=LINE] [--uses=NAME]
+// LINE is a line of the listing in the comment above the program
+fn main() {
+    let context = Context::create();
+    let mut compiler = Compiler::new(&context);
+
+    let shadowing = std::env::args().find_map(|arg| {
+        arg.strip_prefix("--shadowing=")
+            .map(|mode| mode.to_string())
+    });
+    compiler.shadowing = match shadowing.as_deref() {
+        None | Some("warn") => Shadowing::Warn,
+        Some("allow") => Shadowing::Allow,
+        Some("deny") => Shadowing::Deny,
+        Some(mode) => {
+            println!("Error: unknown shadowing mode {mode}");
+            return;
+        }
+    };
+
+    let program = match std::env::args().nth(1).as_deref() {
+        Some("defer") => defer_program(),
+        Some("redeclare") => redeclare_program(),
+        Some("shadow") => shadow_program(),
+        Some("typo") => typo_program(),
+        Some("loop_locals") => loop_locals_program(),
+        Some("sibling_scopes") => sibling_scopes_program(),
+        _ => program(),
+    };
+
+    let compiled = compiler.compile_program(&program);
+    for warning in compiler.warnings.borrow().iter() {
+        println!("Warning: {warning}");
+    }
+    if let Err(e) = compiled {
+        println!("Error: {e}");
+        return;
+    }
+
+    compiler.module.verify().unwrap();
     compiler.module.print_to_file(Path::new("main.ll")).unwrap();
+
+    // Queries answered from the scope tree of the compiled program
+    let scopes = compiler.scopes.borrow();
+    let visible_at = std::env::args().find_map(|arg| {
+        arg.strip_prefix("--visible-at=")
+            .map(|line| line.to_string())
+    });
+    if let Some(line) = visible_at {
+        let line: ListingLine = match line.parse() {
+            Ok(line) => line,
+            Err(_) => {
+                println!("Error: invalid line {line}");
+                return;
+            }
+        };
+        for symbol in scopes.visible_at(line) {
+            println!(
+                "{} {:?} declared on line {}",
+                symbol.name, symbol.pointer.data_type, symbol.line
+            );
+        }
+    }
+    let uses =
+        std::env::args().find_map(|arg| arg.strip_prefix("--uses=").map(|name| name.to_string()));
+    if let Some(name) = uses {
+        for symbol in scopes.symbols_named(&name) {
+            println!(
+                "{} declared on line {} is used on lines {:?}",
+                name, symbol.line, symbol.uses
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use inkwell::targets::{InitializationConfig, Target};
+    use inkwell::values::InstructionOpcode;
+    use inkwell::OptimizationLevel;
+
+    fn compile<'ctx>(context: &'ctx Context, stmts: &[Stmt]) -> Compiler<'ctx> {
+        let compiler = Compiler::new(context);
+        compiler.compile_program(stmts).unwrap();
+        compiler.module.verify().unwrap();
+        compiler
+    }
+
+    fn run(compiler: &Compiler) -> i32 {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let engine = compiler
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        unsafe {
+            let main = engine
+                .get_function::<unsafe extern "C" fn() -> i32>(MAIN_FUNC_NAME)
+                .unwrap();
+            main.call()
+        }
+    }
+
+    #[test]
+    fn loop_locals_are_allocated_in_the_entry_block() {
+        let context = Context::create();
+        let compiler = compile(&context, &loop_locals_program());
+        for block in compiler.main_func.get_basic_blocks().into_iter().skip(1) {
+            let mut instruction = block.get_first_instruction();
+            while let Some(current) = instruction {
+                assert_ne!(current.get_opcode(), InstructionOpcode::Alloca);
+                instruction = current.get_next_instruction();
+            }
+        }
+        assert_eq!(run(&compiler), 0);
+    }
+
+    #[test]
+    fn sibling_scopes_have_disjoint_lifetimes() {
+        let context = Context::create();
+        let compiler = compile(&context, &sibling_scopes_program());
+        let ir = compiler.module.print_to_string().to_string();
+        let marker = |intrinsic: &str, name: &str| {
+            let operand = format!(" %{name})");
+            ir.lines()
+                .position(|line| line.contains(intrinsic) && line.ends_with(&operand))
+                .unwrap_or_else(|| panic!("no {intrinsic} for {name} in\n{ir}"))
+        };
+        let start_a = marker("llvm.lifetime.start", "a");
+        let end_a = marker("llvm.lifetime.end", "a");
+        let start_b = marker("llvm.lifetime.start", "b");
+        let end_b = marker("llvm.lifetime.end", "b");
+        assert!(start_a < end_a && end_a < start_b && start_b < end_b);
+        assert_eq!(run(&compiler), 0);
+    }
+
+    //
+    // int main() {
+    //     int i = 0;
+    //     while (i < 3) {
+    //         defer {
+    //             int last = i;
+    //             printf("%d\n", last);
+    //         }
+    //         i = i + 1;
+    //         if (i == 2) {
+    //             continue;
+    //         }
+    //     }
+    //     return i;
+    // }
+    //
+    #[test]
+    fn deferred_declarations_are_registered_once() {
+        let stmts = vec![
+            declare("i", num(0)),
+            Stmt::While {
+                cond: binary(BinaryOp::Lt, var("i"), num(3)),
+                body: vec![
+                    defer(Stmt::Block(vec![
+                        declare("last", var("i")),
+                        print("%d\n", vec![var("last")]),
+                    ])),
+                    assign("i", binary(BinaryOp::Add, var("i"), num(1))),
+                    Stmt::If {
+                        cond: binary(BinaryOp::Eq, var("i"), num(2)),
+                        then_body: vec![Stmt::Continue],
+                        else_body: vec![],
+                    },
+                ],
+            },
+            Stmt::Return(var("i")),
+        ];
+        let context = Context::create();
+        let compiler = compile(&context, &stmts);
+        {
+            let scopes = compiler.scopes.borrow();
+            let last = scopes.symbols_named("last");
+            assert_eq!(last.len(), 1);
+            assert_eq!((last[0].line, last[0].uses.clone()), (5, vec![6]));
+            let visible: Vec<&str> = scopes
+                .visible_at(6)
+                .iter()
+                .map(|symbol| symbol.name.as_str())
+                .collect();
+            assert_eq!(visible, ["last", "i"]);
+        }
+        assert_eq!(run(&compiler), 3);
+    }
+
+    // The error and the warnings of a program compiled under the given mode
+    fn diagnostics(stmts: &[Stmt], shadowing: Shadowing) -> (Option<String>, Vec<String>) {
+        let context = Context::create();
+        let mut compiler = Compiler::new(&context);
+        compiler.shadowing = shadowing;
+        let error = compiler.compile_program(stmts).err();
+        let warnings = compiler.warnings.borrow().clone();
+        (error, warnings)
+    }
+
+    #[test]
+    fn redeclaration_in_the_same_scope_is_an_error() {
+        let (error, _) = diagnostics(&redeclare_program(), Shadowing::Warn);
+        assert_eq!(
+            error.as_deref(),
+            Some("redeclaration of a in the same scope")
+        );
+    }
+
+    #[test]
+    fn shadowing_is_silent_when_allowed() {
+        assert_eq!(
+            diagnostics(&shadow_program(), Shadowing::Allow),
+            (None, vec![])
+        );
+    }
+
+    #[test]
+    fn shadowing_warns_by_default() {
+        assert_eq!(
+            diagnostics(&shadow_program(), Shadowing::Warn),
+            (
+                None,
+                vec!["count shadows a variable of an outer scope".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn shadowing_is_an_error_when_denied() {
+        let (error, warnings) = diagnostics(&shadow_program(), Shadowing::Deny);
+        assert_eq!(
+            error.as_deref(),
+            Some("count shadows a variable of an outer scope")
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn undefined_variables_suggest_a_close_name() {
+        let (error, _) = diagnostics(&typo_program(), Shadowing::Warn);
+        assert_eq!(
+            error.as_deref(),
+            Some("undefined variable totl, did you mean total?")
+        );
+    }
+
+    thread_local! {
+        static PRINTED: RefCell<Vec<i32>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Stands in for printf("%d\n", value). The call site is variadic, which
+    // on x86-64 only adds the vector register count in al for the callee to
+    // ignore.
+    extern "C" fn record_print(_fmt: *const std::ffi::c_char, value: i32) -> i32 {
+        PRINTED.with(|printed| printed.borrow_mut().push(value));
+        0
+    }
+
+    // Runs the program and returns its exit code with the values it printed
+    fn run_printing(stmts: &[Stmt]) -> (i32, Vec<i32>) {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let context = Context::create();
+        let compiler = compile(&context, stmts);
+        let engine = compiler
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        engine.add_global_mapping(&compiler.printf_func, record_print as *const () as usize);
+        PRINTED.with(|printed| printed.borrow_mut().clear());
+        let code = unsafe {
+            let main = engine
+                .get_function::<unsafe extern "C" fn() -> i32>(MAIN_FUNC_NAME)
+                .unwrap();
+            main.call()
+        };
+        (code, PRINTED.with(|printed| printed.take()))
+    }
+
+    fn print_value(value: Expr) -> Stmt {
+        print("%d\n", vec![value])
+    }
+
+    fn forever() -> Expr {
+        binary(BinaryOp::Lt, num(0), num(1))
+    }
+
+    //
+    // { defer print(1); defer print(2); { defer print(3); print(0); } print(4); }
+    //
+    #[test]
+    fn defers_run_last_in_first_out_when_a_scope_ends() {
+        let stmts = vec![
+            Stmt::Block(vec![
+                defer(print_value(num(1))),
+                defer(print_value(num(2))),
+                Stmt::Block(vec![defer(print_value(num(3))), print_value(num(0))]),
+                print_value(num(4)),
+            ]),
+            Stmt::Return(num(0)),
+        ];
+        assert_eq!(run_printing(&stmts), (0, vec![0, 3, 4, 2, 1]));
+    }
+
+    //
+    // while (0 < 1) { defer print(1); defer print(2); { defer print(3); break; } }
+    // print(4);
+    //
+    #[test]
+    fn break_runs_the_defers_of_every_scope_it_leaves() {
+        let stmts = vec![
+            Stmt::While {
+                cond: forever(),
+                body: vec![
+                    defer(print_value(num(1))),
+                    defer(print_value(num(2))),
+                    Stmt::Block(vec![defer(print_value(num(3))), Stmt::Break]),
+                ],
+            },
+            print_value(num(4)),
+            Stmt::Return(num(0)),
+        ];
+        assert_eq!(run_printing(&stmts), (0, vec![3, 2, 1, 4]));
+    }
+
+    //
+    // int i = 0;
+    // while (i < 2) {
+    //     i = i + 1;
+    //     defer print(i); defer print(i + 10);
+    //     { defer print(i + 20); continue; }
+    // }
+    //
+    #[test]
+    fn continue_runs_the_defers_of_the_iteration() {
+        let plus = |n| binary(BinaryOp::Add, var("i"), num(n));
+        let stmts = vec![
+            declare("i", num(0)),
+            Stmt::While {
+                cond: binary(BinaryOp::Lt, var("i"), num(2)),
+                body: vec![
+                    assign("i", plus(1)),
+                    defer(print_value(var("i"))),
+                    defer(print_value(plus(10))),
+                    Stmt::Block(vec![defer(print_value(plus(20))), Stmt::Continue]),
+                ],
+            },
+            Stmt::Return(var("i")),
+        ];
+        assert_eq!(run_printing(&stmts), (2, vec![21, 11, 1, 22, 12, 2]));
+    }
+
+    //
+    // defer print(1);
+    // { defer print(2); while (0 < 1) { defer print(3); return 7; } }
+    //
+    #[test]
+    fn return_runs_the_defers_of_the_whole_function() {
+        let stmts = vec![
+            defer(print_value(num(1))),
+            Stmt::Block(vec![
+                defer(print_value(num(2))),
+                Stmt::While {
+                    cond: forever(),
+                    body: vec![defer(print_value(num(3))), Stmt::Return(num(7))],
+                },
+            ]),
+            Stmt::Return(num(0)),
+        ];
+        assert_eq!(run_printing(&stmts), (7, vec![3, 2, 1]));
+    }
 }
@@ -0,0 +1,86 @@
+use crate::ast::*;
+use crate::Compiler;
+
+use inkwell::attributes::{Attribute, AttributeLoc};
+use inkwell::module::Linkage;
+use inkwell::values::FunctionValue;
+use std::collections::HashSet;
+
+//
+// Function annotations map to LLVM attributes and linkage:
+//
+//   inline    alwaysinline
+//   noinline  noinline
+//   pure      readonly nounwind
+//   static    internal linkage
+//   extern    declaration only
+//
+// Internal functions left without callers after inlining are dropped by
+// global dead code elimination, clang -O1 and above.
+//
+impl<'ctx> Compiler<'ctx> {
+    pub fn apply_annotations(
+        &self,
+        func: FunctionValue<'ctx>,
+        function: &Function,
+    ) -> Result<(), String> {
+        if function.has_annotation(Annotation::Inline)
+            && function.has_annotation(Annotation::NoInline)
+        {
+            return Err(format!(
+                "function {} cannot be both inline and noinline",
+                function.name
+            ));
+        }
+
+        for annotation in &function.annotations {
+            match annotation {
+                Annotation::Inline => self.add_function_attribute(func, "alwaysinline"),
+                Annotation::NoInline => self.add_function_attribute(func, "noinline"),
+                Annotation::Pure => {
+                    // A tuple returned in memory is written through the sret pointer
+                    if !self.returns_in_memory(&function.return_type) {
+                        self.add_function_attribute(func, "readonly");
+                    }
+                    self.add_function_attribute(func, "nounwind");
+                }
+                Annotation::Static => func.set_linkage(Linkage::Internal),
+                Annotation::Extern => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn add_function_attribute(&self, func: FunctionValue<'ctx>, name: &str) {
+        let attribute = self
+            .context
+            .create_enum_attribute(Attribute::get_named_enum_kind_id(name), 0);
+        func.add_attribute(AttributeLoc::Function, attribute);
+    }
+}
+
+// A pure function may not print, throw or create closures, and may only call
+// other pure functions
+pub fn check_pure(function: &Function, pure: &HashSet<&str>) -> Result<(), String> {
+    let mut error = None;
+    for stmt in &function.body {
+        stmt.walk(&mut |node| {
+            let reason = match node {
+                Node::Stmt(Stmt::Print(..)) => Some("prints".to_string()),
+                Node::Stmt(Stmt::Throw { .. }) => Some("throws".to_string()),
+                Node::Expr(Expr::Lambda { .. }) => Some("creates a closure".to_string()),
+                Node::Expr(Expr::Call(name, _)) if !pure.contains(name.as_str()) => {
+                    Some(format!("calls {name} which is not pure"))
+                }
+                _ => None,
+            };
+            if error.is_none() {
+                error = reason;
+            }
+        });
+    }
+    match error {
+        Some(reason) => Err(format!("pure function {} {}", function.name, reason)),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,379 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Double,
+    // char *, a pointer to a C string
+    Str,
+    Void,
+    // Pointer to a function, int (*)(int, int)
+    Function {
+        params: Vec<Type>,
+        return_type: Box<Type>,
+    },
+    // Only returned by functions, (int, int)
+    Tuple(Vec<Type>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i64),
+    Double(f64),
+    // A string literal
+    Str(String),
+    Variable(String),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    // &name, the address of a named function
+    FunctionRef(String),
+    // The next int argument of a variadic function
+    VaArg,
+    // [=](params) -> return_type { body }
+    Lambda {
+        params: Vec<Param>,
+        return_type: Type,
+        body: Vec<Stmt>,
+    },
+    // (a, b), only returned from a function returning a tuple
+    Tuple(Vec<Expr>),
+}
+
+// A statement or expression visited by walk
+pub enum Node<'a> {
+    Stmt(&'a Stmt),
+    Expr(&'a Expr),
+}
+
+impl Expr {
+    // Visits this expression and everything nested in it, lambda bodies included
+    pub fn walk(&self, visit: &mut dyn FnMut(Node)) {
+        visit(Node::Expr(self));
+        match self {
+            Expr::Number(_)
+            | Expr::Double(_)
+            | Expr::Str(_)
+            | Expr::Variable(_)
+            | Expr::VaArg
+            | Expr::FunctionRef(_) => {}
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.walk(visit);
+                rhs.walk(visit);
+            }
+            Expr::Call(_, args) | Expr::Tuple(args) => {
+                for arg in args {
+                    arg.walk(visit);
+                }
+            }
+            Expr::Lambda { body, .. } => {
+                for stmt in body {
+                    stmt.walk(visit);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Declare(String, Expr),
+    // auto [a, b] = f(); declares a variable for each value of the tuple
+    Destructure(Vec<String>, Expr),
+    Assign(String, Expr),
+    Print(String, Vec<Expr>),
+    Expr(Expr),
+    Block(Vec<Stmt>),
+    If {
+        cond: Expr,
+        then_body: Vec<Stmt>,
+        else_body: Vec<Stmt>,
+    },
+    While {
+        cond: Expr,
+        body: Vec<Stmt>,
+    },
+    Return(Option<Expr>),
+    Label(String),
+    Goto(String),
+    // throw Class(value);
+    Throw {
+        class: String,
+        value: Expr,
+    },
+    Try {
+        body: Vec<Stmt>,
+        catches: Vec<Catch>,
+    },
+}
+
+// catch (Class name) { body }
+#[derive(Debug, Clone)]
+pub struct Catch {
+    pub class: String,
+    pub name: String,
+    pub body: Vec<Stmt>,
+}
+
+impl Stmt {
+    pub fn walk(&self, visit: &mut dyn FnMut(Node)) {
+        visit(Node::Stmt(self));
+        let (exprs, stmts): (Vec<&Expr>, Vec<&Stmt>) = match self {
+            Stmt::Declare(_, value)
+            | Stmt::Destructure(_, value)
+            | Stmt::Assign(_, value)
+            | Stmt::Expr(value)
+            | Stmt::Throw { value, .. } => (vec![value], vec![]),
+            Stmt::Print(_, args) => (args.iter().collect(), vec![]),
+            Stmt::Block(stmts) => (vec![], stmts.iter().collect()),
+            Stmt::If {
+                cond,
+                then_body,
+                else_body,
+            } => (vec![cond], then_body.iter().chain(else_body).collect()),
+            Stmt::While { cond, body } => (vec![cond], body.iter().collect()),
+            Stmt::Return(value) => (value.iter().collect(), vec![]),
+            Stmt::Label(_) | Stmt::Goto(_) => (vec![], vec![]),
+            Stmt::Try { body, catches } => (
+                vec![],
+                body.iter()
+                    .chain(catches.iter().flat_map(|catch| &catch.body))
+                    .collect(),
+            ),
+        };
+        for expr in exprs {
+            expr.walk(visit);
+        }
+        for stmt in stmts {
+            stmt.walk(visit);
+        }
+    }
+
+    // Names of the variables and closures the statement refers to
+    pub fn variables(&self, names: &mut Vec<String>) {
+        self.walk(&mut |node| match node {
+            Node::Expr(Expr::Variable(name))
+            | Node::Expr(Expr::Call(name, _))
+            | Node::Stmt(Stmt::Assign(name, _)) => names.push(name.clone()),
+            _ => {}
+        });
+    }
+
+    // Names of the labels defined anywhere in the statement
+    pub fn labels(&self, names: &mut Vec<String>) {
+        self.walk(&mut |node| {
+            if let Node::Stmt(Stmt::Label(name)) = node {
+                names.push(name.clone());
+            }
+        });
+    }
+
+    // Code following a return or goto is still reachable when it holds a label
+    pub fn contains_label(&self) -> bool {
+        match self {
+            Stmt::Label(_) => true,
+            Stmt::Block(stmts) | Stmt::While { body: stmts, .. } => {
+                stmts.iter().any(|stmt| stmt.contains_label())
+            }
+            Stmt::If {
+                then_body,
+                else_body,
+                ..
+            } => then_body
+                .iter()
+                .chain(else_body)
+                .any(|stmt| stmt.contains_label()),
+            Stmt::Try { body, catches } => body
+                .iter()
+                .chain(catches.iter().flat_map(|catch| &catch.body))
+                .any(|stmt| stmt.contains_label()),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub name: String,
+    pub param_type: Type,
+}
+
+// Written before the return type, static inline int f(int x)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Annotation {
+    // Always inlined into its callers
+    Inline,
+    // Never inlined
+    NoInline,
+    // Only reads memory, no output or exceptions, calls only pure functions
+    Pure,
+    // Not visible outside of the module
+    Static,
+    // Declared without a body, defined in a C library linked in
+    Extern,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub return_type: Type,
+    // Takes further int arguments after params, read with va_arg
+    pub variadic: bool,
+    pub annotations: Vec<Annotation>,
+    pub body: Vec<Stmt>,
+}
+
+impl Function {
+    // The Type::Function of a pointer to this function
+    pub fn signature(&self) -> Type {
+        function_ptr(
+            self.params
+                .iter()
+                .map(|param| param.param_type.clone())
+                .collect(),
+            self.return_type.clone(),
+        )
+    }
+
+    pub fn has_annotation(&self, annotation: Annotation) -> bool {
+        self.annotations.contains(&annotation)
+    }
+}
+
+pub fn num(value: i64) -> Expr {
+    Expr::Number(value)
+}
+
+pub fn double(value: f64) -> Expr {
+    Expr::Double(value)
+}
+
+pub fn string(value: &str) -> Expr {
+    Expr::Str(value.to_string())
+}
+
+pub fn var(name: &str) -> Expr {
+    Expr::Variable(name.to_string())
+}
+
+pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+}
+
+pub fn call(name: &str, args: Vec<Expr>) -> Expr {
+    Expr::Call(name.to_string(), args)
+}
+
+pub fn tuple(values: Vec<Expr>) -> Expr {
+    Expr::Tuple(values)
+}
+
+pub fn declare(name: &str, value: Expr) -> Stmt {
+    Stmt::Declare(name.to_string(), value)
+}
+
+pub fn destructure(names: &[&str], value: Expr) -> Stmt {
+    Stmt::Destructure(names.iter().map(|name| name.to_string()).collect(), value)
+}
+
+pub fn assign(name: &str, value: Expr) -> Stmt {
+    Stmt::Assign(name.to_string(), value)
+}
+
+pub fn print(fmt: &str, args: Vec<Expr>) -> Stmt {
+    Stmt::Print(fmt.to_string(), args)
+}
+
+pub fn ret(value: Expr) -> Stmt {
+    Stmt::Return(Some(value))
+}
+
+pub fn label(name: &str) -> Stmt {
+    Stmt::Label(name.to_string())
+}
+
+pub fn goto(name: &str) -> Stmt {
+    Stmt::Goto(name.to_string())
+}
+
+pub fn throw(class: &str, value: Expr) -> Stmt {
+    Stmt::Throw {
+        class: class.to_string(),
+        value,
+    }
+}
+
+pub fn catch(class: &str, name: &str, body: Vec<Stmt>) -> Catch {
+    Catch {
+        class: class.to_string(),
+        name: name.to_string(),
+        body,
+    }
+}
+
+pub fn function_ref(name: &str) -> Expr {
+    Expr::FunctionRef(name.to_string())
+}
+
+pub fn function_ptr(params: Vec<Type>, return_type: Type) -> Type {
+    Type::Function {
+        params,
+        return_type: Box::new(return_type),
+    }
+}
+
+pub fn lambda(params: Vec<Param>, return_type: Type, body: Vec<Stmt>) -> Expr {
+    Expr::Lambda {
+        params,
+        return_type,
+        body,
+    }
+}
+
+pub fn param(name: &str, param_type: Type) -> Param {
+    Param {
+        name: name.to_string(),
+        param_type,
+    }
+}
+
+pub fn function(name: &str, params: Vec<Param>, return_type: Type, body: Vec<Stmt>) -> Function {
+    Function {
+        name: name.to_string(),
+        params,
+        return_type,
+        variadic: false,
+        annotations: vec![],
+        body,
+    }
+}
+
+pub fn annotate(annotations: Vec<Annotation>, function: Function) -> Function {
+    Function {
+        annotations,
+        ..function
+    }
+}
+
+pub fn variadic_function(
+    name: &str,
+    params: Vec<Param>,
+    return_type: Type,
+    body: Vec<Stmt>,
+) -> Function {
+    Function {
+        variadic: true,
+        ..function(name, params, return_type, body)
+    }
+}
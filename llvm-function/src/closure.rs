@@ -0,0 +1,236 @@
+use crate::ast::*;
+use crate::env::*;
+use crate::{Compiler, Lambda};
+
+use inkwell::module::Linkage;
+use inkwell::types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FunctionType, StructType};
+use inkwell::values::{BasicValueEnum, CallSiteValue, FunctionValue};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+//
+// A closure is a { ptr, ptr } pair of the lambda function and its environment,
+// a heap allocated struct holding a copy of every captured variable:
+//
+//   int base = 10;
+//   auto add = [=](int x) -> int { return x + base; };
+//
+// becomes
+//
+//   int main.lambda.0(struct { int base; } *env, int x) {
+//       int base = env->base;
+//       return x + base;
+//   }
+//
+// The environment is never freed. Copies of a closure share it and nothing
+// tracks when the last one goes away, see the known limitation in README.md.
+//
+impl<'ctx> Compiler<'ctx> {
+    pub fn closure_type(&self) -> StructType<'ctx> {
+        self.context
+            .struct_type(&[self.ptr_type().into(), self.ptr_type().into()], false)
+    }
+
+    fn closure_fn_type(&self, params: &[Type], return_type: &Type) -> FunctionType<'ctx> {
+        let mut param_types: Vec<BasicMetadataTypeEnum> = vec![self.ptr_type().into()];
+        param_types.extend(
+            params
+                .iter()
+                .filter_map(|param| self.basic_type(param))
+                .map(BasicMetadataTypeEnum::from),
+        );
+        match self.basic_type(return_type) {
+            Some(return_type) => return_type.fn_type(&param_types, false),
+            None => self.context.void_type().fn_type(&param_types, false),
+        }
+    }
+
+    // Every variable of the enclosing scopes the body refers to, except for
+    // the lambda's own parameters
+    fn captures(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        params: &[Param],
+        body: &[Stmt],
+    ) -> Vec<(String, Pointer<'ctx>)> {
+        let mut names = Vec::new();
+        body.iter().for_each(|stmt| stmt.variables(&mut names));
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .filter(|name| params.iter().all(|param| &param.name != name))
+            .filter_map(|name| env.borrow().get(&name).map(|pointer| (name, pointer)))
+            .collect()
+    }
+
+    pub fn compile_lambda(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        params: &[Param],
+        return_type: &Type,
+        body: &[Stmt],
+    ) -> Result<BasicValueEnum<'ctx>, String> {
+        let enclosing = self.current_function();
+        let name = format!(
+            "{}.lambda.{}",
+            enclosing.get_name().to_string_lossy(),
+            self.lambda_count.get()
+        );
+        self.lambda_count.set(self.lambda_count.get() + 1);
+
+        for param in params {
+            let kind = match param.param_type {
+                Type::Void => "void",
+                Type::Tuple(_) => "a tuple",
+                _ => continue,
+            };
+            return Err(format!(
+                "parameter {} of {} cannot be {}",
+                param.name, name, kind
+            ));
+        }
+        if let Type::Tuple(_) = return_type {
+            return Err(format!("{name} cannot return a tuple"));
+        }
+        let param_types: Vec<Type> = params
+            .iter()
+            .map(|param| param.param_type.clone())
+            .collect();
+        let fn_type = self.closure_fn_type(&param_types, return_type);
+        let func = self
+            .module
+            .add_function(&name, fn_type, Some(Linkage::Private));
+
+        // Copy the captured variables into the environment
+        let captures = self.captures(env, params, body);
+        let field_types: Vec<BasicTypeEnum> = captures
+            .iter()
+            .map(|(_, pointer)| self.llvm_type(&pointer.data_type))
+            .collect();
+        let env_type = self.context.struct_type(&field_types, false);
+        let env_ptr = if captures.is_empty() {
+            self.ptr_type().const_null()
+        } else {
+            let fn_type = self
+                .ptr_type()
+                .fn_type(&[self.context.i64_type().into()], false);
+            let env_ptr = self
+                .call_runtime("malloc", fn_type, &[env_type.size_of().unwrap().into()])
+                .unwrap()
+                .into_pointer_value();
+            for (i, (name, pointer)) in captures.iter().enumerate() {
+                let value =
+                    self.builder
+                        .build_load(self.llvm_type(&pointer.data_type), pointer.ptr, name);
+                let field = self
+                    .builder
+                    .build_struct_gep(env_type, env_ptr, i as u32, name)
+                    .unwrap();
+                self.builder.build_store(field, value);
+            }
+            env_ptr
+        };
+
+        let closure = self.closure_type().get_undef();
+        let closure = self
+            .builder
+            .build_insert_value(
+                closure,
+                func.as_global_value().as_pointer_value(),
+                0,
+                "closure",
+            )
+            .unwrap();
+        let closure = self
+            .builder
+            .build_insert_value(closure, env_ptr, 1, "closure")
+            .unwrap();
+
+        self.lambdas.borrow_mut().push(Lambda {
+            func,
+            function: function(&name, params.to_vec(), return_type.clone(), body.to_vec()),
+            captures: captures
+                .into_iter()
+                .map(|(name, pointer)| (name, pointer.data_type))
+                .collect(),
+            env_type,
+        });
+        Ok(closure.into_struct_value().into())
+    }
+
+    // Unpacks the environment into locals of the lambda
+    pub fn load_captures(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        func: FunctionValue<'ctx>,
+        lambda: &Lambda<'ctx>,
+    ) {
+        let env_ptr = func.get_nth_param(0).unwrap().into_pointer_value();
+        for (i, (name, data_type)) in lambda.captures.iter().enumerate() {
+            let field = self
+                .builder
+                .build_struct_gep(lambda.env_type, env_ptr, i as u32, name)
+                .unwrap();
+            let value = self
+                .builder
+                .build_load(self.llvm_type(data_type), field, name);
+            let ptr = self.builder.build_alloca(self.llvm_type(data_type), name);
+            self.builder.build_store(ptr, value);
+            env.borrow_mut().add(
+                name,
+                Pointer {
+                    ptr,
+                    data_type: data_type.clone(),
+                },
+            );
+        }
+    }
+
+    pub fn compile_closure_call(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        name: &str,
+        pointer: &Pointer<'ctx>,
+        args: &[Expr],
+    ) -> Result<CallSiteValue<'ctx>, String> {
+        let (params, return_type) = match &pointer.data_type {
+            DataType::Closure {
+                params,
+                return_type,
+            } => (params, return_type),
+            _ => return Err(format!("{name} is not a function")),
+        };
+        if args.len() != params.len() {
+            return Err(format!(
+                "closure {} expects {} arguments, got {}",
+                name,
+                params.len(),
+                args.len()
+            ));
+        }
+        self.check_args(env, name, params, args, false)?;
+
+        let closure = self
+            .builder
+            .build_load(self.closure_type(), pointer.ptr, name)
+            .into_struct_value();
+        let func_ptr = self
+            .builder
+            .build_extract_value(closure, 0, "func")
+            .unwrap()
+            .into_pointer_value();
+        let env_ptr = self.builder.build_extract_value(closure, 1, "env").unwrap();
+
+        let mut call_args = vec![env_ptr];
+        call_args.extend(self.compile_args(env, params, args)?);
+
+        // Void values cannot be named
+        let call_name = match return_type {
+            Type::Void => "",
+            _ => name,
+        };
+        let fn_type = self.closure_fn_type(params, return_type);
+        Ok(self.build_indirect_call_or_invoke(fn_type, func_ptr, &call_args, call_name))
+    }
+}
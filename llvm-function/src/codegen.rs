@@ -0,0 +1,1137 @@
+use crate::annotation::check_pure;
+use crate::ast::*;
+use crate::env::*;
+use crate::tuple::TupleAbi;
+use crate::{Compiler, Label, Lambda, MAIN_FUNC_NAME};
+
+use inkwell::basic_block::BasicBlock;
+use inkwell::intrinsics::Intrinsic;
+use inkwell::targets::TargetMachine;
+use inkwell::types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FunctionType, PointerType};
+use inkwell::values::{
+    BasicMetadataValueEnum, BasicValueEnum, CallSiteValue, FunctionValue, IntValue, PointerValue,
+};
+use inkwell::{AddressSpace, FloatPredicate, IntPredicate};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+//
+// CallingConv::Tail, the number LLVMSetFunctionCallConv takes for tailcc.
+//
+// The LangRef guarantees that a call marked `tail` in tail position is turned
+// into a jump when both caller and callee use tailcc, at every optimization
+// level, so recursion through such calls runs in constant stack even at -O0.
+// Only the `tail` hint is emitted: LLVM 15's C API cannot mark calls
+// `musttail`, which would additionally make the verifier reject a call that
+// cannot become a jump. A `tail` call outside of tail position is silently
+// left a normal call, so in_tail_position decides which calls get marked.
+//
+const TAIL_CALL_CONV: u32 = 18;
+
+fn float_predicate(op: BinaryOp) -> Option<FloatPredicate> {
+    match op {
+        BinaryOp::Lt => Some(FloatPredicate::OLT),
+        BinaryOp::Le => Some(FloatPredicate::OLE),
+        BinaryOp::Gt => Some(FloatPredicate::OGT),
+        BinaryOp::Ge => Some(FloatPredicate::OGE),
+        BinaryOp::Eq => Some(FloatPredicate::OEQ),
+        BinaryOp::Ne => Some(FloatPredicate::ONE),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => None,
+    }
+}
+
+fn int_predicate(op: BinaryOp) -> Option<IntPredicate> {
+    match op {
+        BinaryOp::Lt => Some(IntPredicate::SLT),
+        BinaryOp::Le => Some(IntPredicate::SLE),
+        BinaryOp::Gt => Some(IntPredicate::SGT),
+        BinaryOp::Ge => Some(IntPredicate::SGE),
+        BinaryOp::Eq => Some(IntPredicate::EQ),
+        BinaryOp::Ne => Some(IntPredicate::NE),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => None,
+    }
+}
+
+impl<'ctx> Compiler<'ctx> {
+    // None for void
+    pub fn basic_type(&self, ty: &Type) -> Option<BasicTypeEnum<'ctx>> {
+        match ty {
+            Type::Int => Some(self.i32_type.into()),
+            Type::Double => Some(self.context.f64_type().into()),
+            Type::Str => Some(self.ptr_type().into()),
+            Type::Void => None,
+            Type::Function { .. } => Some(self.ptr_type().into()),
+            Type::Tuple(elements) => Some(self.tuple_type(elements.len()).into()),
+        }
+    }
+
+    pub fn llvm_type(&self, data_type: &DataType) -> BasicTypeEnum<'ctx> {
+        match data_type {
+            DataType::Number => self.i32_type.into(),
+            DataType::Double => self.context.f64_type().into(),
+            DataType::Str => self.ptr_type().into(),
+            DataType::Closure { .. } => self.closure_type().into(),
+            DataType::Function(_) => self.ptr_type().into(),
+        }
+    }
+
+    pub fn ptr_type(&self) -> PointerType<'ctx> {
+        self.context.i8_type().ptr_type(AddressSpace::default())
+    }
+
+    // The module is compiled for the host. Where the lowering follows the C
+    // ABI of x86-64 System V rather than asking LLVM, other hosts are refused
+    // instead of miscompiled.
+    pub fn check_sysv_target(&self, feature: &str) -> Result<(), String> {
+        let triple = TargetMachine::get_default_triple();
+        let triple = triple.as_str().to_string_lossy();
+        if triple.starts_with("x86_64") && !triple.contains("windows") {
+            Ok(())
+        } else {
+            Err(format!(
+                "{feature} are only supported on x86-64 System V targets, not {triple}"
+            ))
+        }
+    }
+
+    // Functions of the C and C++ runtime, declared on first use
+    pub fn runtime_function(&self, name: &str, fn_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        self.module
+            .get_function(name)
+            .unwrap_or_else(|| self.module.add_function(name, fn_type, None))
+    }
+
+    pub fn call_runtime(
+        &self,
+        name: &str,
+        fn_type: FunctionType<'ctx>,
+        args: &[BasicValueEnum<'ctx>],
+    ) -> Option<BasicValueEnum<'ctx>> {
+        let func = self.runtime_function(name, fn_type);
+        let args: Vec<_> = args.iter().map(|arg| (*arg).into()).collect();
+        self.builder
+            .build_call(func, &args, "")
+            .try_as_basic_value()
+            .left()
+    }
+
+    pub fn function_type(&self, function: &Function) -> Result<FunctionType<'ctx>, String> {
+        for param in &function.params {
+            let kind = match param.param_type {
+                Type::Void => "void",
+                Type::Tuple(_) => "a tuple",
+                _ => continue,
+            };
+            return Err(format!(
+                "parameter {} of function {} cannot be {}",
+                param.name, function.name, kind
+            ));
+        }
+        self.check_tuple(function)?;
+        Ok(self.llvm_function_type(&function.signature(), function.variadic))
+    }
+
+    // The LLVM type of a Type::Function
+    pub fn llvm_function_type(&self, signature: &Type, variadic: bool) -> FunctionType<'ctx> {
+        let (params, return_type) = match signature {
+            Type::Function {
+                params,
+                return_type,
+            } => (params, return_type),
+            _ => unreachable!("not a function type: {signature:?}"),
+        };
+        let mut param_types: Vec<BasicMetadataTypeEnum> = params
+            .iter()
+            .filter_map(|param| self.basic_type(param))
+            .map(|param_type| param_type.into())
+            .collect();
+        if let Type::Tuple(elements) = &**return_type {
+            return match self.tuple_abi(elements.len()) {
+                TupleAbi::Direct(return_type) => return_type.fn_type(&param_types, variadic),
+                TupleAbi::Indirect => {
+                    param_types.insert(0, self.ptr_type().into());
+                    self.context.void_type().fn_type(&param_types, variadic)
+                }
+            };
+        }
+        match self.basic_type(return_type) {
+            Some(return_type) => return_type.fn_type(&param_types, variadic),
+            None => self.context.void_type().fn_type(&param_types, variadic),
+        }
+    }
+
+    pub fn current_function(&self) -> FunctionValue<'ctx> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .unwrap()
+    }
+
+    // A block is terminated once a return or branch has been emitted into it,
+    // anything generated after that would be dead code
+    pub fn is_terminated(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some()
+    }
+
+    fn branch_to(&self, block: BasicBlock<'ctx>) {
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(block);
+        }
+    }
+
+    //
+    // Allocas outside of the entry block are executed every time control
+    // passes them, a declaration in a loop or a function turned into a loop
+    // would keep growing the stack
+    //
+    pub fn build_entry_alloca<T: BasicType<'ctx>>(&self, ty: T, name: &str) -> PointerValue<'ctx> {
+        let entry_block = self.current_function().get_first_basic_block().unwrap();
+        let builder = self.context.create_builder();
+        match entry_block.get_first_instruction() {
+            Some(instruction) => builder.position_before(&instruction),
+            None => builder.position_at_end(entry_block),
+        }
+        builder.build_alloca(ty, name)
+    }
+
+    fn merge_block(
+        &self,
+        func: FunctionValue<'ctx>,
+        merge_block: &mut Option<BasicBlock<'ctx>>,
+    ) -> BasicBlock<'ctx> {
+        *merge_block.get_or_insert_with(|| self.context.append_basic_block(func, "merge"))
+    }
+
+    fn call_intrinsic(&self, name: &str, args: &[BasicMetadataValueEnum<'ctx>]) {
+        let intrinsic = Intrinsic::find(name).unwrap();
+        let func = intrinsic.get_declaration(&self.module, &[]).unwrap();
+        self.builder.build_call(func, args, "");
+    }
+
+    //
+    // The va_list lives in the entry block for the whole call. Its size and
+    // layout are target specific, the 32 bytes allocated here hold the 24 byte
+    // va_list of x86-64 System V, and va_arg reads it the way that ABI does.
+    //
+    fn start_varargs(&self) -> Result<(), String> {
+        self.check_sysv_target("variadic functions")?;
+        let va_list_type = self.context.i64_type().array_type(4);
+        let va_list = self.builder.build_alloca(va_list_type, "va_list");
+        self.call_intrinsic("llvm.va_start", &[va_list.into()]);
+        self.va_list.set(Some(va_list));
+        Ok(())
+    }
+
+    // Every return of a variadic function releases its va_list
+    pub fn end_varargs(&self) {
+        if let Some(va_list) = self.va_list.get() {
+            self.call_intrinsic("llvm.va_end", &[va_list.into()]);
+        }
+    }
+
+    //
+    // Every prototype is declared before any body is compiled, so that a
+    // function can call itself and functions defined after it.
+    //
+    pub fn compile_program(&self, functions: &[Function]) -> Result<(), String> {
+        // Functions whose address is taken may be called from C
+        let mut address_taken = HashSet::new();
+        for stmt in functions.iter().flat_map(|function| &function.body) {
+            stmt.walk(&mut |node| {
+                if let Node::Expr(Expr::FunctionRef(name)) = node {
+                    address_taken.insert(name.clone());
+                }
+            });
+        }
+
+        // A name is pure when every overload of it is
+        let impure: HashSet<&str> = functions
+            .iter()
+            .filter(|function| !function.has_annotation(Annotation::Pure))
+            .map(|function| function.name.as_str())
+            .collect();
+        let pure: HashSet<&str> = functions
+            .iter()
+            .map(|function| function.name.as_str())
+            .filter(|name| !impure.contains(name))
+            .collect();
+        for function in functions {
+            if function.has_annotation(Annotation::Pure) {
+                check_pure(function, &pure)?;
+            }
+        }
+
+        let mut overloaded = HashSet::new();
+        let mut seen = HashSet::new();
+        for function in functions {
+            if !seen.insert(function.name.as_str()) {
+                overloaded.insert(function.name.as_str());
+            }
+        }
+
+        let mut funcs = Vec::new();
+        for function in functions {
+            // Functions visible outside of the module keep the C calling
+            // convention
+            let func =
+                self.declare_function(function, overloaded.contains(function.name.as_str()))?;
+            if function.has_annotation(Annotation::Static)
+                && !function.variadic
+                && !address_taken.contains(&function.name)
+            {
+                func.set_call_conventions(TAIL_CALL_CONV);
+            }
+            funcs.push(func);
+        }
+        for (function, func) in functions.iter().zip(funcs) {
+            if function.has_annotation(Annotation::Extern) {
+                continue;
+            }
+            self.compile_function(func, function, None)?;
+        }
+
+        // Lambdas may in turn contain lambdas
+        loop {
+            let lambda = self.lambdas.borrow_mut().pop();
+            match lambda {
+                Some(lambda) => {
+                    self.compile_function(lambda.func, &lambda.function, Some(&lambda))?;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    // Overloads are declared under their mangled name, other functions keep
+    // theirs so that C can call them
+    fn declare_function(
+        &self,
+        function: &Function,
+        overloaded: bool,
+    ) -> Result<FunctionValue<'ctx>, String> {
+        let func = if function.name == MAIN_FUNC_NAME {
+            if overloaded {
+                return Err(format!("function {MAIN_FUNC_NAME} cannot be overloaded"));
+            }
+            if function.variadic {
+                return Err(format!("function {MAIN_FUNC_NAME} cannot be variadic"));
+            }
+            if function.has_annotation(Annotation::Static) {
+                return Err(format!("function {MAIN_FUNC_NAME} cannot be static"));
+            }
+            if function.has_annotation(Annotation::Extern) {
+                return Err(format!("function {MAIN_FUNC_NAME} cannot be extern"));
+            }
+            self.main_func
+        } else if function.has_annotation(Annotation::Extern) {
+            if overloaded {
+                return Err(format!(
+                    "extern function {} cannot be overloaded",
+                    function.name
+                ));
+            }
+            self.declare_extern(function)?
+        } else {
+            let symbol = if overloaded {
+                let params: Vec<Type> = function
+                    .params
+                    .iter()
+                    .map(|param| param.param_type.clone())
+                    .collect();
+                mangle(&function.name, &params, function.variadic)
+            } else {
+                function.name.clone()
+            };
+            if !overloaded && self.module.get_function(&symbol).is_some() {
+                return Err(format!(
+                    "function {} clashes with a runtime function",
+                    function.name
+                ));
+            }
+            let fn_type = self.function_type(function)?;
+            let func = self.module.add_function(&symbol, fn_type, None);
+            self.add_sret_attribute(func, function);
+            func
+        };
+        self.functions.borrow_mut().add(
+            &function.name,
+            FunctionEntry {
+                func,
+                signature: function.signature(),
+                variadic: function.variadic,
+                is_extern: function.has_annotation(Annotation::Extern),
+            },
+        )?;
+        self.apply_annotations(func, function)?;
+        Ok(func)
+    }
+
+    //
+    // extern double sqrt(double x); is left for the linker to resolve. A
+    // declaration of a runtime function, printf for example, refers to it
+    // and must have the same type.
+    //
+    fn declare_extern(&self, function: &Function) -> Result<FunctionValue<'ctx>, String> {
+        if !function.body.is_empty() {
+            return Err(format!(
+                "extern function {} cannot have a body",
+                function.name
+            ));
+        }
+        if function.has_annotation(Annotation::Static) {
+            return Err(format!(
+                "extern function {} cannot be static",
+                function.name
+            ));
+        }
+        let fn_type = self.function_type(function)?;
+        match self.module.get_function(&function.name) {
+            Some(func) if func.get_type() == fn_type => Ok(func),
+            Some(_) => Err(format!(
+                "extern function {} conflicts with the runtime function of the same name",
+                function.name
+            )),
+            None => {
+                let func = self.module.add_function(&function.name, fn_type, None);
+                self.add_sret_attribute(func, function);
+                Ok(func)
+            }
+        }
+    }
+
+    fn compile_function(
+        &self,
+        func: FunctionValue<'ctx>,
+        function: &Function,
+        lambda: Option<&Lambda<'ctx>>,
+    ) -> Result<FunctionValue<'ctx>, String> {
+        let entry_block = self.context.append_basic_block(func, "entry");
+        self.builder.position_at_end(entry_block);
+        self.labels.borrow_mut().clear();
+        *self.return_type.borrow_mut() = function.return_type.clone();
+        self.handlers.borrow_mut().clear();
+        self.exception_slots.set(None);
+        self.active_catches.borrow_mut().clear();
+        self.va_list.set(None);
+        self.sret.set(self.sret_param(func, function));
+        if function.name == MAIN_FUNC_NAME {
+            self.push_uncaught_handler();
+        }
+
+        let env = Rc::new(RefCell::new(Env::new(None)));
+        if let Some(lambda) = lambda {
+            self.load_captures(&env, func, lambda);
+        }
+
+        // A lambda takes its environment as hidden first parameter, a function
+        // returning a tuple in memory the sret pointer
+        let param_offset = if lambda.is_some() || self.sret.get().is_some() {
+            1
+        } else {
+            0
+        };
+        for (i, param) in function.params.iter().enumerate() {
+            let param_type = self.basic_type(&param.param_type).unwrap();
+            let ptr = self.builder.build_alloca(param_type, &param.name);
+            let value = func.get_nth_param((i + param_offset) as u32).unwrap();
+            self.builder.build_store(ptr, value);
+            env.borrow_mut().add(
+                &param.name,
+                Pointer {
+                    ptr,
+                    data_type: DataType::from_type(&param.param_type).unwrap(),
+                },
+            );
+        }
+
+        if function.variadic {
+            self.start_varargs()?;
+        }
+
+        self.compile_stmts(&env, &function.body)?;
+
+        for (name, label) in self.labels.borrow().iter() {
+            if !label.defined {
+                return Err(format!(
+                    "undefined label {} in function {}",
+                    name, function.name
+                ));
+            }
+        }
+
+        // The body fell off the end of the function
+        if !self.is_terminated() {
+            if function.return_type == Type::Void {
+                self.end_varargs();
+                self.builder.build_return(None);
+            } else if function.name == MAIN_FUNC_NAME {
+                self.builder
+                    .build_return(Some(&self.i32_type.const_int(0, false)));
+            } else {
+                return Err(format!("missing return in function {}", function.name));
+            }
+        }
+
+        if function.name == MAIN_FUNC_NAME {
+            if let Some(dispatch_block) = self.pop_handler() {
+                self.compile_uncaught(dispatch_block)?;
+            }
+        }
+        Ok(func)
+    }
+
+    fn lookup(&self, env: &Rc<RefCell<Env<'ctx>>>, name: &str) -> Result<Pointer<'ctx>, String> {
+        env.borrow()
+            .get(name)
+            .ok_or_else(|| format!("undefined variable {name}"))
+    }
+
+    // Expressions are numbers unless they produce a double, string, closure or
+    // function pointer
+    fn data_type(&self, env: &Rc<RefCell<Env<'ctx>>>, expr: &Expr) -> Result<DataType, String> {
+        Ok(match expr {
+            Expr::Double(_) => DataType::Double,
+            Expr::Str(_) => DataType::Str,
+            Expr::Variable(name) => self.lookup(env, name)?.data_type,
+            // Comparisons are numbers, arithmetic has the type of its operands
+            Expr::Binary(op, lhs, _) => match int_predicate(*op) {
+                Some(_) => DataType::Number,
+                None => self.data_type(env, lhs)?,
+            },
+            Expr::Lambda {
+                params,
+                return_type,
+                ..
+            } => DataType::Closure {
+                params: params
+                    .iter()
+                    .map(|param| param.param_type.clone())
+                    .collect(),
+                return_type: return_type.clone(),
+            },
+            Expr::FunctionRef(name) => {
+                DataType::Function(self.functions.borrow().unique(name)?.signature)
+            }
+            Expr::Call(name, args) => {
+                let variable = env.borrow().get(name);
+                let return_type = match variable.map(|pointer| pointer.data_type) {
+                    Some(DataType::Closure { return_type, .. }) => return_type,
+                    Some(DataType::Function(Type::Function { return_type, .. })) => *return_type,
+                    _ => match self.resolve_call(env, name, args) {
+                        Ok(entry) => entry.return_type().clone(),
+                        Err(_) => Type::Int,
+                    },
+                };
+                match (DataType::from_type(&return_type), return_type) {
+                    (Some(data_type), _) => data_type,
+                    (None, Type::Void) => {
+                        return Err(format!("void function {name} used as a value"))
+                    }
+                    (None, _) => {
+                        return Err(format!("tuple returned by {name} must be destructured"))
+                    }
+                }
+            }
+            _ => DataType::Number,
+        })
+    }
+
+    //
+    // The function a call to name picks. A name without overloads keeps the
+    // arity and argument errors pointing at what is wrong, overloads are
+    // ranked by the data types of the arguments:
+    //
+    //   void show(int x);
+    //   void show(double x);
+    //
+    //   show(1);      calls show(int)
+    //   show(1.5);    calls show(double)
+    //
+    pub fn resolve_call(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        name: &str,
+        args: &[Expr],
+    ) -> Result<FunctionEntry<'ctx>, String> {
+        let overloads = self.functions.borrow().overloads(name);
+        let entry = match overloads.as_slice() {
+            [] => return Err(format!("undefined function {name}")),
+            [entry] => entry.clone(),
+            _ => {
+                let mut arg_types = Vec::new();
+                for arg in args {
+                    arg_types.push(self.data_type(env, arg)?);
+                }
+                return self.functions.borrow().resolve(name, &arg_types);
+            }
+        };
+
+        let param_count = entry.params().len();
+        if entry.variadic {
+            if args.len() < param_count {
+                return Err(format!(
+                    "function {} expects at least {} arguments, got {}",
+                    name,
+                    param_count,
+                    args.len()
+                ));
+            }
+        } else if args.len() != param_count {
+            return Err(format!(
+                "function {} expects {} arguments, got {}",
+                name,
+                param_count,
+                args.len()
+            ));
+        }
+        self.check_args(env, name, entry.params(), args, entry.is_extern)?;
+        Ok(entry)
+    }
+
+    // Every argument must have the data type of its parameter or convert to
+    // it, extra arguments of a variadic function are numbers, or also doubles
+    // and strings for a C function
+    pub fn check_args(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        name: &str,
+        params: &[Type],
+        args: &[Expr],
+        is_extern: bool,
+    ) -> Result<(), String> {
+        for (i, arg) in args.iter().enumerate() {
+            let data_type = self.data_type(env, arg)?;
+            let accepted = match params.get(i) {
+                Some(param) => DataType::from_type(param)
+                    .is_some_and(|param| conversion_cost(&data_type, &param).is_some()),
+                None => takes_extra(&data_type, is_extern),
+            };
+            if !accepted {
+                return Err(format!(
+                    "argument {} of {} has the wrong type: {:?}",
+                    i + 1,
+                    name,
+                    arg
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compile_expr(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        expr: &Expr,
+    ) -> Result<BasicValueEnum<'ctx>, String> {
+        match expr {
+            Expr::Number(value) => Ok(self.i32_type.const_int(*value as u64, true).into()),
+            Expr::Double(value) => Ok(self.context.f64_type().const_float(*value).into()),
+            Expr::Str(value) => {
+                let str = unsafe { self.builder.build_global_string(value, "str") };
+                Ok(str.as_pointer_value().into())
+            }
+            Expr::Variable(name) => {
+                let pointer = self.lookup(env, name)?;
+                let load_type = self.llvm_type(&pointer.data_type);
+                Ok(self.builder.build_load(load_type, pointer.ptr, name))
+            }
+            Expr::Binary(op, lhs, rhs) if self.data_type(env, lhs)? == DataType::Double => {
+                self.compile_float_binary(env, *op, lhs, rhs)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.compile_int(env, lhs)?;
+                let rhs = self.compile_int(env, rhs)?;
+                let value = match op {
+                    BinaryOp::Add => self.builder.build_int_add(lhs, rhs, "add"),
+                    BinaryOp::Sub => self.builder.build_int_sub(lhs, rhs, "sub"),
+                    BinaryOp::Mul => self.builder.build_int_mul(lhs, rhs, "mul"),
+                    _ => {
+                        let predicate = int_predicate(*op).unwrap();
+                        let cmp = self.builder.build_int_compare(predicate, lhs, rhs, "cmp");
+                        self.builder.build_int_z_extend(cmp, self.i32_type, "cmp")
+                    }
+                };
+                Ok(value.into())
+            }
+            Expr::VaArg => {
+                let func = self.current_function();
+                let va_list = self.va_list.get().ok_or_else(|| {
+                    format!(
+                        "va_arg used in non-variadic function {}",
+                        func.get_name().to_string_lossy()
+                    )
+                })?;
+                Ok(self.builder.build_va_arg(va_list, self.i32_type, "va_arg"))
+            }
+            Expr::Lambda {
+                params,
+                return_type,
+                body,
+            } => self.compile_lambda(env, params, return_type, body),
+            Expr::FunctionRef(name) => {
+                let entry = self.functions.borrow().unique(name)?;
+                // A function pointer type has no room for the extra arguments
+                if entry.variadic {
+                    return Err(format!("variadic function {name} cannot be referenced"));
+                }
+                if let Type::Tuple(_) = entry.return_type() {
+                    return Err(format!(
+                        "function {name} returns a tuple and cannot be referenced"
+                    ));
+                }
+                Ok(entry.func.as_global_value().as_pointer_value().into())
+            }
+            Expr::Call(name, args) => self
+                .compile_call(env, name, args)?
+                .ok_or_else(|| format!("void function {name} used as a value")),
+            Expr::Tuple(_) => Err(format!("tuple used as a value: {expr:?}")),
+        }
+    }
+
+    fn compile_float_binary(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        op: BinaryOp,
+        lhs: &Expr,
+        rhs: &Expr,
+    ) -> Result<BasicValueEnum<'ctx>, String> {
+        if self.data_type(env, rhs)? != DataType::Double {
+            return Err(format!("expected a double expression: {rhs:?}"));
+        }
+        let lhs = self.compile_expr(env, lhs)?.into_float_value();
+        let rhs = self.compile_expr(env, rhs)?.into_float_value();
+        let value = match op {
+            BinaryOp::Add => self.builder.build_float_add(lhs, rhs, "add").into(),
+            BinaryOp::Sub => self.builder.build_float_sub(lhs, rhs, "sub").into(),
+            BinaryOp::Mul => self.builder.build_float_mul(lhs, rhs, "mul").into(),
+            _ => {
+                let predicate = float_predicate(op).unwrap();
+                let cmp = self.builder.build_float_compare(predicate, lhs, rhs, "cmp");
+                self.builder
+                    .build_int_z_extend(cmp, self.i32_type, "cmp")
+                    .into()
+            }
+        };
+        Ok(value)
+    }
+
+    pub fn compile_int(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        expr: &Expr,
+    ) -> Result<IntValue<'ctx>, String> {
+        match self.compile_expr(env, expr)? {
+            BasicValueEnum::IntValue(value) => Ok(value),
+            _ => Err(format!("expected an integer expression: {expr:?}")),
+        }
+    }
+
+    // Comparisons are branched on directly, anything else is true when non-zero
+    fn compile_cond(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        expr: &Expr,
+    ) -> Result<IntValue<'ctx>, String> {
+        if let Expr::Binary(op, lhs, rhs) = expr {
+            if let Some(predicate) = int_predicate(*op) {
+                if self.data_type(env, lhs)? == DataType::Number {
+                    let lhs = self.compile_int(env, lhs)?;
+                    let rhs = self.compile_int(env, rhs)?;
+                    return Ok(self.builder.build_int_compare(predicate, lhs, rhs, "cond"));
+                }
+            }
+        }
+        let value = self.compile_int(env, expr)?;
+        Ok(self.builder.build_int_compare(
+            IntPredicate::NE,
+            value,
+            self.i32_type.const_zero(),
+            "cond",
+        ))
+    }
+
+    // Returns None for calls to void functions
+    fn compile_call(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        name: &str,
+        args: &[Expr],
+    ) -> Result<Option<BasicValueEnum<'ctx>>, String> {
+        Ok(self
+            .compile_call_site(env, name, args)?
+            .try_as_basic_value()
+            .left())
+    }
+
+    fn compile_call_site(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        name: &str,
+        args: &[Expr],
+    ) -> Result<CallSiteValue<'ctx>, String> {
+        // Variables shadow functions of the same name
+        let variable = env.borrow().get(name);
+        if let Some(pointer) = variable {
+            return match &pointer.data_type {
+                DataType::Function(signature) => {
+                    self.compile_indirect_call(env, name, &pointer, signature, args)
+                }
+                _ => self.compile_closure_call(env, name, &pointer, args),
+            };
+        }
+
+        let entry = self.resolve_call(env, name, args)?;
+        if let Type::Tuple(_) = entry.return_type() {
+            return Err(format!("tuple returned by {name} must be destructured"));
+        }
+        let call_args = self.compile_args(env, entry.params(), args)?;
+        let func = entry.func;
+
+        // Void values cannot be named
+        let call_name = match func.get_type().get_return_type() {
+            Some(_) => name,
+            None => "",
+        };
+        Ok(self.build_call_or_invoke(func, &call_args, call_name))
+    }
+
+    // The arguments of a call, ints passed to double parameters are
+    // converted
+    pub fn compile_args(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        params: &[Type],
+        args: &[Expr],
+    ) -> Result<Vec<BasicValueEnum<'ctx>>, String> {
+        let mut call_args: Vec<BasicValueEnum> = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let value = self.compile_expr(env, arg)?;
+            let value = match (params.get(i), value) {
+                (Some(Type::Double), BasicValueEnum::IntValue(value)) => self
+                    .builder
+                    .build_signed_int_to_float(value, self.context.f64_type(), "conv")
+                    .into(),
+                (_, value) => value,
+            };
+            call_args.push(value);
+        }
+        Ok(call_args)
+    }
+
+    //
+    // f(args) where f is a variable holding a function pointer. The pointer
+    // carries no type at runtime, the signature comes from the variable.
+    //
+    fn compile_indirect_call(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        name: &str,
+        pointer: &Pointer<'ctx>,
+        signature: &Type,
+        args: &[Expr],
+    ) -> Result<CallSiteValue<'ctx>, String> {
+        let (params, return_type) = match signature {
+            Type::Function {
+                params,
+                return_type,
+            } => (params, return_type),
+            _ => unreachable!("not a function type: {signature:?}"),
+        };
+        if args.len() != params.len() {
+            return Err(format!(
+                "function pointer {} expects {} arguments, got {}",
+                name,
+                params.len(),
+                args.len()
+            ));
+        }
+        self.check_args(env, name, params, args, false)?;
+
+        let func_ptr = self
+            .builder
+            .build_load(self.ptr_type(), pointer.ptr, name)
+            .into_pointer_value();
+        let call_args = self.compile_args(env, params, args)?;
+
+        // Void values cannot be named
+        let call_name = match **return_type {
+            Type::Void => "",
+            _ => name,
+        };
+        let fn_type = self.llvm_function_type(signature, false);
+        Ok(self.build_indirect_call_or_invoke(fn_type, func_ptr, &call_args, call_name))
+    }
+
+    // Statements following a return or goto are unreachable and skipped,
+    // unless a goto can still jump to a label inside them
+    pub fn compile_stmts(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        stmts: &[Stmt],
+    ) -> Result<(), String> {
+        for stmt in stmts {
+            if self.is_terminated() {
+                if !stmt.contains_label() {
+                    continue;
+                }
+                if !matches!(stmt, Stmt::Label(_)) {
+                    let func = self.current_function();
+                    let unreachable_block = self.context.append_basic_block(func, "unreachable");
+                    self.builder.position_at_end(unreachable_block);
+                }
+            }
+            self.compile_stmt(env, stmt)?;
+        }
+        Ok(())
+    }
+
+    fn label_block(&self, name: &str) -> BasicBlock<'ctx> {
+        let func = self.current_function();
+        self.labels
+            .borrow_mut()
+            .entry(name.to_string())
+            .or_insert_with(|| Label {
+                block: self.context.append_basic_block(func, name),
+                defined: false,
+            })
+            .block
+    }
+
+    //
+    // name:
+    //
+    // The label block may already exist when a goto jumped forward to it. The
+    // current block falls through into it.
+    //
+    fn compile_label(&self, name: &str) -> Result<(), String> {
+        let block = self.label_block(name);
+        if let Some(label) = self.labels.borrow_mut().get_mut(name) {
+            if label.defined {
+                let func = self.current_function();
+                return Err(format!(
+                    "duplicate label {} in function {}",
+                    name,
+                    func.get_name().to_string_lossy()
+                ));
+            }
+            label.defined = true;
+        }
+        self.branch_to(block);
+        block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(block);
+        Ok(())
+    }
+
+    // Every block gets its own Env so that declarations do not leak out of it
+    pub fn compile_block(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        stmts: &[Stmt],
+    ) -> Result<(), String> {
+        let block_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
+        self.compile_stmts(&block_env, stmts)
+    }
+
+    pub fn compile_stmt(&self, env: &Rc<RefCell<Env<'ctx>>>, stmt: &Stmt) -> Result<(), String> {
+        match stmt {
+            Stmt::Declare(name, value) => {
+                let data_type = self.data_type(env, value)?;
+                let value = self.compile_expr(env, value)?;
+                let ptr = self.build_entry_alloca(value.get_type(), name);
+                self.builder.build_store(ptr, value);
+                env.borrow_mut().add(name, Pointer { ptr, data_type });
+            }
+            Stmt::Destructure(names, value) => self.compile_destructure(env, names, value)?,
+            Stmt::Assign(name, value) => {
+                let pointer = self.lookup(env, name)?;
+                if self.data_type(env, value)? != pointer.data_type {
+                    return Err(format!("type mismatch in assignment to {name}"));
+                }
+                let value = self.compile_expr(env, value)?;
+                self.builder.build_store(pointer.ptr, value);
+            }
+            Stmt::Print(fmt, args) => {
+                let fmt_str = unsafe { self.builder.build_global_string(fmt, "fmt_str") };
+                let mut call_args: Vec<BasicMetadataValueEnum> =
+                    vec![fmt_str.as_pointer_value().into()];
+                for arg in args {
+                    call_args.push(self.compile_expr(env, arg)?.into());
+                }
+                self.builder
+                    .build_call(self.printf_func, &call_args, "printf");
+            }
+            Stmt::Expr(Expr::Call(name, args)) => {
+                self.compile_call(env, name, args)?;
+            }
+            Stmt::Expr(expr) => {
+                self.compile_expr(env, expr)?;
+            }
+            Stmt::Block(stmts) => self.compile_block(env, stmts)?,
+            Stmt::If {
+                cond,
+                then_body,
+                else_body,
+            } => self.compile_if(env, cond, then_body, else_body)?,
+            Stmt::While { cond, body } => self.compile_while(env, cond, body)?,
+            Stmt::Return(value) => self.compile_return(env, value.as_ref())?,
+            Stmt::Label(name) => self.compile_label(name)?,
+            Stmt::Goto(name) => {
+                let block = self.label_block(name);
+                self.end_catches_left_by(name);
+                self.builder.build_unconditional_branch(block);
+            }
+            Stmt::Throw { class, value } => self.compile_throw(env, class, value)?,
+            Stmt::Try { body, catches } => self.compile_try(env, body, catches)?,
+        }
+        Ok(())
+    }
+
+    // A call directly followed by the return can reuse the caller's stack
+    // frame, unless it is an invoke or cleanup code has to run after it
+    fn in_tail_position(&self) -> bool {
+        self.handlers.borrow().is_empty()
+            && self.active_catches.borrow().is_empty()
+            && self.va_list.get().is_none()
+    }
+
+    fn compile_return(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        value: Option<&Expr>,
+    ) -> Result<(), String> {
+        let return_type = self.return_type.borrow().clone();
+        if let Type::Tuple(elements) = return_type {
+            return self.compile_tuple_return(env, elements.len(), value);
+        }
+
+        let func = self.current_function();
+        let name = func.get_name().to_string_lossy();
+        match (value, func.get_type().get_return_type()) {
+            (Some(value), Some(_)) => {
+                if Some(self.data_type(env, value)?)
+                    != DataType::from_type(&self.return_type.borrow())
+                {
+                    return Err(format!("wrong type of return value in function {name}"));
+                }
+                let value = match value {
+                    // Marked tail, not musttail, see TAIL_CALL_CONV
+                    Expr::Call(callee, args) if self.in_tail_position() => {
+                        let call = self.compile_call_site(env, callee, args)?;
+                        call.set_tail_call(true);
+                        call.try_as_basic_value().left().unwrap()
+                    }
+                    _ => self.compile_expr(env, value)?,
+                };
+                self.end_active_catches();
+                self.end_varargs();
+                self.builder.build_return(Some(&value));
+            }
+            (None, None) => {
+                self.end_active_catches();
+                self.end_varargs();
+                self.builder.build_return(None);
+            }
+            (Some(_), None) => {
+                return Err(format!("void function {name} cannot return a value"));
+            }
+            (None, Some(_)) => {
+                return Err(format!("missing return value in function {name}"));
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // The merge block is only created once an arm falls through to it, so
+    // when both arms return the builder is left in a terminated block and the
+    // rest of the enclosing block is dropped as dead code.
+    //
+    fn compile_if(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        cond: &Expr,
+        then_body: &[Stmt],
+        else_body: &[Stmt],
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let comparison = self.compile_cond(env, cond)?;
+
+        let mut merge_block = None;
+        let if_true_block = self.context.append_basic_block(func, "if_true");
+        let if_false_block = if else_body.is_empty() {
+            self.merge_block(func, &mut merge_block)
+        } else {
+            self.context.append_basic_block(func, "if_false")
+        };
+        self.builder
+            .build_conditional_branch(comparison, if_true_block, if_false_block);
+
+        // Generate code for if true block
+        self.builder.position_at_end(if_true_block);
+        self.compile_block(env, then_body)?;
+        if !self.is_terminated() {
+            let merge_block = self.merge_block(func, &mut merge_block);
+            self.builder.build_unconditional_branch(merge_block);
+        }
+
+        // Generate code for if false block
+        if !else_body.is_empty() {
+            if_false_block
+                .move_after(self.builder.get_insert_block().unwrap())
+                .unwrap();
+            self.builder.position_at_end(if_false_block);
+            self.compile_block(env, else_body)?;
+            if !self.is_terminated() {
+                let merge_block = self.merge_block(func, &mut merge_block);
+                self.builder.build_unconditional_branch(merge_block);
+            }
+        }
+
+        // Generate code for merge block
+        if let Some(merge_block) = merge_block {
+            merge_block
+                .move_after(self.builder.get_insert_block().unwrap())
+                .unwrap();
+            self.builder.position_at_end(merge_block);
+        }
+        Ok(())
+    }
+
+    fn compile_while(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        cond: &Expr,
+        body: &[Stmt],
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let cond_block = self.context.append_basic_block(func, "cond");
+        let body_block = self.context.append_basic_block(func, "body");
+        let loop_end_block = self.context.append_basic_block(func, "loop_end");
+
+        self.builder.build_unconditional_branch(cond_block);
+
+        self.builder.position_at_end(cond_block);
+        let comparison = self.compile_cond(env, cond)?;
+        self.builder
+            .build_conditional_branch(comparison, body_block, loop_end_block);
+
+        // Generate code for body block
+        self.builder.position_at_end(body_block);
+        self.compile_block(env, body)?;
+        self.branch_to(cond_block);
+
+        // Generate code for loop end block
+        loop_end_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(loop_end_block);
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use crate::ast::Type;
+use inkwell::values::{FunctionValue, PointerValue};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataType {
+    Number,
+    Double,
+    Str,
+    // A function pointer and environment pair
+    Closure {
+        params: Vec<Type>,
+        return_type: Type,
+    },
+    // Always a Type::Function
+    Function(Type),
+}
+
+impl DataType {
+    // None for void and tuples, which cannot be stored in a variable
+    pub fn from_type(ty: &Type) -> Option<DataType> {
+        match ty {
+            Type::Int => Some(DataType::Number),
+            Type::Double => Some(DataType::Double),
+            Type::Str => Some(DataType::Str),
+            Type::Void => None,
+            Type::Function { .. } => Some(DataType::Function(ty.clone())),
+            Type::Tuple(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pointer<'ctx> {
+    pub ptr: PointerValue<'ctx>,
+    pub data_type: DataType,
+}
+
+pub struct Env<'ctx> {
+    parent: Option<Rc<RefCell<Env<'ctx>>>>,
+    symbols: HashMap<String, Pointer<'ctx>>,
+}
+
+impl<'ctx> Env<'ctx> {
+    pub fn new(parent: Option<Rc<RefCell<Env<'ctx>>>>) -> Self {
+        Self {
+            parent,
+            symbols: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, ptr: Pointer<'ctx>) {
+        self.symbols.insert(name.to_string(), ptr);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Pointer<'ctx>> {
+        match self.symbols.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.parent.as_ref().and_then(|o| o.borrow().get(name)),
+        }
+    }
+}
+
+// Cost of passing an argument to a parameter, None if it cannot be passed.
+// An int converts to a double.
+pub fn conversion_cost(arg: &DataType, param: &DataType) -> Option<usize> {
+    match (arg, param) {
+        _ if arg == param => Some(0),
+        (DataType::Number, DataType::Double) => Some(1),
+        _ => None,
+    }
+}
+
+// Whether extra arguments of a variadic function may have this type, C
+// functions also take doubles and strings
+pub fn takes_extra(arg: &DataType, is_extern: bool) -> bool {
+    match arg {
+        DataType::Number => true,
+        DataType::Double | DataType::Str => is_extern,
+        _ => false,
+    }
+}
+
+// One function of the program, overloads share a name
+#[derive(Debug, Clone)]
+pub struct FunctionEntry<'ctx> {
+    pub func: FunctionValue<'ctx>,
+    // Always a Type::Function
+    pub signature: Type,
+    pub variadic: bool,
+    pub is_extern: bool,
+}
+
+impl<'ctx> FunctionEntry<'ctx> {
+    pub fn params(&self) -> &[Type] {
+        match &self.signature {
+            Type::Function { params, .. } => params,
+            _ => unreachable!("not a function type: {:?}", self.signature),
+        }
+    }
+
+    pub fn return_type(&self) -> &Type {
+        match &self.signature {
+            Type::Function { return_type, .. } => return_type,
+            _ => unreachable!("not a function type: {:?}", self.signature),
+        }
+    }
+
+    // How well arguments of these types match the parameters, lower is
+    // better: passing through the ellipsis is worse than any conversion
+    fn rank(&self, args: &[DataType]) -> Option<(bool, usize)> {
+        let params = self.params();
+        if args.len() < params.len() || (args.len() > params.len() && !self.variadic) {
+            return None;
+        }
+        let mut conversions = 0;
+        for (arg, param) in args.iter().zip(params) {
+            conversions += conversion_cost(arg, &DataType::from_type(param)?)?;
+        }
+        let extra = &args[params.len()..];
+        if !extra.iter().all(|arg| takes_extra(arg, self.is_extern)) {
+            return None;
+        }
+        Some((!extra.is_empty(), conversions))
+    }
+}
+
+//
+// The function namespace, separate from the variables of Env. A name maps to
+// every overload of it:
+//
+//   void show(int x);
+//   void show(double x);
+//
+// which are emitted under mangled symbol names, _Z4showi and _Z4showd.
+//
+#[derive(Default)]
+pub struct FunctionTable<'ctx> {
+    functions: HashMap<String, Vec<FunctionEntry<'ctx>>>,
+}
+
+impl<'ctx> FunctionTable<'ctx> {
+    pub fn add(&mut self, name: &str, entry: FunctionEntry<'ctx>) -> Result<(), String> {
+        let overloads = self.functions.entry(name.to_string()).or_default();
+        if overloads
+            .iter()
+            .any(|overload| overload.params() == entry.params())
+        {
+            return Err(format!("duplicate function {name}"));
+        }
+        overloads.push(entry);
+        Ok(())
+    }
+
+    pub fn overloads(&self, name: &str) -> Vec<FunctionEntry<'ctx>> {
+        self.functions.get(name).cloned().unwrap_or_default()
+    }
+
+    // The function a name refers to where arguments cannot pick an overload
+    pub fn unique(&self, name: &str) -> Result<FunctionEntry<'ctx>, String> {
+        match self.overloads(name).as_slice() {
+            [] => Err(format!("undefined function {name}")),
+            [entry] => Ok(entry.clone()),
+            _ => Err(format!("function {name} is overloaded")),
+        }
+    }
+
+    // The overload with the best rank for arguments of these types
+    pub fn resolve(&self, name: &str, args: &[DataType]) -> Result<FunctionEntry<'ctx>, String> {
+        let mut ranked: Vec<_> = self
+            .overloads(name)
+            .into_iter()
+            .filter_map(|entry| entry.rank(args).map(|rank| (rank, entry)))
+            .collect();
+        ranked.sort_by_key(|(rank, _)| *rank);
+        match ranked.as_slice() {
+            [] => Err(format!("no overload of {name} takes {args:?}")),
+            [(best, _), (second, _), ..] if best == second => {
+                Err(format!("ambiguous call to {name} with {args:?}"))
+            }
+            [(_, entry), ..] => Ok(entry.clone()),
+        }
+    }
+}
+
+// Itanium C++ mangling of an overloaded function, so that every overload gets
+// a symbol of its own
+pub fn mangle(name: &str, params: &[Type], variadic: bool) -> String {
+    let mut codes: String = params.iter().map(mangle_type).collect();
+    if variadic {
+        codes.push('z');
+    }
+    if codes.is_empty() {
+        codes.push('v');
+    }
+    format!("_Z{}{}{}", name.len(), name, codes)
+}
+
+fn mangle_type(ty: &Type) -> String {
+    match ty {
+        Type::Int => "i".to_string(),
+        Type::Double => "d".to_string(),
+        Type::Str => "Pc".to_string(),
+        Type::Void => "v".to_string(),
+        Type::Tuple(elements) => format!("6tuple{}", elements.len()),
+        Type::Function {
+            params,
+            return_type,
+        } => {
+            let params: String = params.iter().map(mangle_type).collect();
+            let params = if params.is_empty() {
+                "v".to_string()
+            } else {
+                params
+            };
+            format!("PF{}{}E", mangle_type(return_type), params)
+        }
+    }
+}
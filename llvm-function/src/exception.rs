@@ -0,0 +1,498 @@
+use crate::ast::*;
+use crate::env::*;
+use crate::{ActiveCatch, Compiler, Handler};
+
+use inkwell::basic_block::BasicBlock;
+use inkwell::module::Linkage;
+use inkwell::types::{FunctionType, StructType};
+use inkwell::values::{BasicValueEnum, CallSiteValue, FunctionValue, PointerValue};
+use inkwell::IntPredicate;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+//
+// Exceptions follow the Itanium C++ ABI so that the C++ runtime and the system
+// unwinder can be linked in as they are:
+//
+//   throw Class(value)  ->  __cxa_allocate_exception + __cxa_throw
+//   catch (Class name)  ->  a landingpad clause on the type info of Class
+//
+// Thrown values are always ints, the class only selects the catch clause.
+//
+impl<'ctx> Compiler<'ctx> {
+    // { ptr, i32 } as returned by a landingpad
+    fn exception_type(&self) -> StructType<'ctx> {
+        self.context
+            .struct_type(&[self.ptr_type().into(), self.i32_type.into()], false)
+    }
+
+    fn personality_function(&self) -> FunctionValue<'ctx> {
+        self.runtime_function("__gxx_personality_v0", self.i32_type.fn_type(&[], true))
+    }
+
+    fn begin_catch(&self, exception: PointerValue<'ctx>) -> PointerValue<'ctx> {
+        let fn_type = self.ptr_type().fn_type(&[self.ptr_type().into()], false);
+        self.call_runtime("__cxa_begin_catch", fn_type, &[exception.into()])
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    fn end_catch(&self) {
+        let fn_type = self.context.void_type().fn_type(&[], false);
+        self.call_runtime("__cxa_end_catch", fn_type, &[]);
+    }
+
+    // Returning from inside catch bodies finishes every one of them
+    pub fn end_active_catches(&self) {
+        for _ in 0..self.active_catches.borrow().len() {
+            self.end_catch();
+        }
+    }
+
+    // A goto finishes the catch bodies it jumps out of, the label is in every
+    // catch body enclosing the innermost one that defines it
+    pub fn end_catches_left_by(&self, label: &str) {
+        let left = self
+            .active_catches
+            .borrow()
+            .iter()
+            .rev()
+            .take_while(|catch| !catch.labels.iter().any(|name| name == label))
+            .count();
+        for _ in 0..left {
+            self.end_catch();
+        }
+    }
+
+    //
+    // A throw leaves the catch bodies entered since the innermost try region,
+    // a try inside a catch body keeps the exception in it. The exception being
+    // handled is no longer needed once the new one has been allocated.
+    //
+    fn end_catches_left_by_throw(&self) {
+        let depth = self.handlers.borrow().len();
+        let left = self
+            .active_catches
+            .borrow()
+            .iter()
+            .rev()
+            .take_while(|catch| catch.handler_depth == depth)
+            .count();
+        for _ in 0..left {
+            self.end_catch();
+        }
+    }
+
+    //
+    // The type info of a class, emitted like clang does for
+    //
+    // struct Class {};
+    //
+    // linkonce_odr lets every module that throws or catches Class define it.
+    //
+    fn type_info(&self, class: &str) -> PointerValue<'ctx> {
+        let mangled = format!("{}{}", class.len(), class);
+        let type_info_name = format!("_ZTI{mangled}");
+        if let Some(type_info) = self.module.get_global(&type_info_name) {
+            return type_info.as_pointer_value();
+        }
+
+        let name = self.context.const_string(mangled.as_bytes(), true);
+        let name_global = self
+            .module
+            .add_global(name.get_type(), None, &format!("_ZTS{mangled}"));
+        name_global.set_initializer(&name);
+        name_global.set_constant(true);
+        name_global.set_linkage(Linkage::LinkOnceODR);
+
+        // The type info starts two entries into the vtable of __class_type_info
+        let vtable_name = "_ZTVN10__cxxabiv117__class_type_infoE";
+        let vtable = self
+            .module
+            .get_global(vtable_name)
+            .unwrap_or_else(|| self.module.add_global(self.ptr_type(), None, vtable_name));
+        let vtable_ptr = unsafe {
+            vtable.as_pointer_value().const_gep(
+                self.ptr_type(),
+                &[self.context.i64_type().const_int(2, false)],
+            )
+        };
+
+        let value = self.context.const_struct(
+            &[vtable_ptr.into(), name_global.as_pointer_value().into()],
+            false,
+        );
+        let type_info = self
+            .module
+            .add_global(value.get_type(), None, &type_info_name);
+        type_info.set_initializer(&value);
+        type_info.set_constant(true);
+        type_info.set_linkage(Linkage::LinkOnceODR);
+        type_info.as_pointer_value()
+    }
+
+    // Allocated in the entry block so that every landing pad shares them
+    fn exception_slots(&self) -> (PointerValue<'ctx>, PointerValue<'ctx>) {
+        if let Some(slots) = self.exception_slots.get() {
+            return slots;
+        }
+        let slots = (
+            self.build_entry_alloca(self.ptr_type(), "exception_slot"),
+            self.build_entry_alloca(self.i32_type, "selector_slot"),
+        );
+        self.exception_slots.set(Some(slots));
+        slots
+    }
+
+    // The landing pad of the innermost try region, created on first use
+    fn landing_pad(&self) -> Option<BasicBlock<'ctx>> {
+        let func = self.current_function();
+        self.handlers.borrow_mut().last_mut().map(|handler| {
+            *handler
+                .landing_pad
+                .get_or_insert_with(|| self.context.append_basic_block(func, "lpad"))
+        })
+    }
+
+    fn invoke_cont_block(&self) -> BasicBlock<'ctx> {
+        let func = self.current_function();
+        let invoke_cont_block = self.context.append_basic_block(func, "invoke_cont");
+        invoke_cont_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        invoke_cont_block
+    }
+
+    // Inside a try region the call unwinds to its landing pad
+    pub fn build_call_or_invoke(
+        &self,
+        function: FunctionValue<'ctx>,
+        args: &[BasicValueEnum<'ctx>],
+        name: &str,
+    ) -> CallSiteValue<'ctx> {
+        let call = match self.landing_pad() {
+            Some(landing_pad) => {
+                let invoke_cont_block = self.invoke_cont_block();
+                let call =
+                    self.builder
+                        .build_invoke(function, args, invoke_cont_block, landing_pad, name);
+                self.builder.position_at_end(invoke_cont_block);
+                call
+            }
+            None => {
+                let args: Vec<_> = args.iter().map(|arg| (*arg).into()).collect();
+                self.builder.build_call(function, &args, name)
+            }
+        };
+        // The call has to use the calling convention of the callee
+        call.set_call_convention(function.get_call_conventions());
+        call
+    }
+
+    pub fn build_indirect_call_or_invoke(
+        &self,
+        fn_type: FunctionType<'ctx>,
+        function_pointer: PointerValue<'ctx>,
+        args: &[BasicValueEnum<'ctx>],
+        name: &str,
+    ) -> CallSiteValue<'ctx> {
+        match self.landing_pad() {
+            Some(landing_pad) => {
+                let invoke_cont_block = self.invoke_cont_block();
+                let call = self.builder.build_indirect_invoke(
+                    fn_type,
+                    function_pointer,
+                    args,
+                    invoke_cont_block,
+                    landing_pad,
+                    name,
+                );
+                self.builder.position_at_end(invoke_cont_block);
+                call
+            }
+            None => {
+                let args: Vec<_> = args.iter().map(|arg| (*arg).into()).collect();
+                self.builder
+                    .build_indirect_call(fn_type, function_pointer, &args, name)
+            }
+        }
+    }
+
+    fn push_handler(&self, type_infos: &[PointerValue<'ctx>]) {
+        let mut handlers = self.handlers.borrow_mut();
+        let mut clauses: Vec<BasicValueEnum> = type_infos
+            .iter()
+            .map(|type_info| (*type_info).into())
+            .collect();
+        if let Some(outer) = handlers.last() {
+            clauses.extend(outer.clauses.iter().copied());
+        }
+        handlers.push(Handler {
+            clauses,
+            landing_pad: None,
+            dispatch: None,
+        });
+    }
+
+    // main catches whatever its callees did not
+    pub fn push_uncaught_handler(&self) {
+        self.push_handler(&[self.ptr_type().const_null()]);
+    }
+
+    //
+    // Emits the landing pad of a popped handler, which saves the exception for
+    // the dispatch block. Returns None when nothing in the region can throw.
+    //
+    pub fn pop_handler(&self) -> Option<BasicBlock<'ctx>> {
+        let handler = self.handlers.borrow_mut().pop().unwrap();
+        let func = self.current_function();
+        let dispatch_block = match (handler.landing_pad, handler.dispatch) {
+            (None, None) => return None,
+            (_, Some(dispatch_block)) => dispatch_block,
+            (Some(_), None) => self.context.append_basic_block(func, "catch_dispatch"),
+        };
+
+        if let Some(landing_pad_block) = handler.landing_pad {
+            let personality = self.personality_function();
+            func.set_personality_function(personality);
+
+            landing_pad_block
+                .move_after(self.builder.get_insert_block().unwrap())
+                .unwrap();
+            self.builder.position_at_end(landing_pad_block);
+            let landing_pad = self
+                .builder
+                .build_landing_pad(
+                    self.exception_type(),
+                    personality,
+                    &handler.clauses,
+                    false,
+                    "landing_pad",
+                )
+                .into_struct_value();
+            let (exception_slot, selector_slot) = self.exception_slots();
+            let exception = self
+                .builder
+                .build_extract_value(landing_pad, 0, "exception")
+                .unwrap();
+            let selector = self
+                .builder
+                .build_extract_value(landing_pad, 1, "selector")
+                .unwrap();
+            self.builder.build_store(exception_slot, exception);
+            self.builder.build_store(selector_slot, selector);
+            self.builder.build_unconditional_branch(dispatch_block);
+        }
+
+        dispatch_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        Some(dispatch_block)
+    }
+
+    fn load_exception(&self) -> PointerValue<'ctx> {
+        let (exception_slot, _) = self.exception_slots();
+        self.builder
+            .build_load(self.ptr_type(), exception_slot, "exception")
+            .into_pointer_value()
+    }
+
+    // An exception none of the catch clauses matched goes on to the enclosing
+    // handler, or out of the function
+    fn compile_unhandled(&self) {
+        let func = self.current_function();
+        let outer_dispatch = self.handlers.borrow_mut().last_mut().map(|handler| {
+            *handler
+                .dispatch
+                .get_or_insert_with(|| self.context.append_basic_block(func, "catch_dispatch"))
+        });
+
+        match outer_dispatch {
+            Some(dispatch_block) => {
+                self.builder.build_unconditional_branch(dispatch_block);
+            }
+            None => {
+                let (exception_slot, selector_slot) = self.exception_slots();
+                let exception =
+                    self.builder
+                        .build_load(self.ptr_type(), exception_slot, "exception");
+                let selector = self
+                    .builder
+                    .build_load(self.i32_type, selector_slot, "selector");
+                let landing_pad = self.exception_type().get_undef();
+                let landing_pad = self
+                    .builder
+                    .build_insert_value(landing_pad, exception, 0, "landing_pad")
+                    .unwrap();
+                let landing_pad = self
+                    .builder
+                    .build_insert_value(landing_pad, selector, 1, "landing_pad")
+                    .unwrap();
+                self.builder.build_resume(landing_pad);
+            }
+        }
+    }
+
+    // throw Class(value);
+    pub fn compile_throw(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        class: &str,
+        value: &Expr,
+    ) -> Result<(), String> {
+        let value = self.compile_int(env, value)?;
+
+        let fn_type = self
+            .ptr_type()
+            .fn_type(&[self.context.i64_type().into()], false);
+        let object = self
+            .call_runtime(
+                "__cxa_allocate_exception",
+                fn_type,
+                &[self.i32_type.size_of().into()],
+            )
+            .unwrap()
+            .into_pointer_value();
+        self.builder.build_store(object, value);
+
+        // No destructor
+        let fn_type = self.context.void_type().fn_type(
+            &[
+                self.ptr_type().into(),
+                self.ptr_type().into(),
+                self.ptr_type().into(),
+            ],
+            false,
+        );
+        let throw_func = self.runtime_function("__cxa_throw", fn_type);
+        self.end_catches_left_by_throw();
+        let args = [
+            object.into(),
+            self.type_info(class).into(),
+            self.ptr_type().const_null().into(),
+        ];
+        self.build_call_or_invoke(throw_func, &args, "");
+        self.builder.build_unreachable();
+        Ok(())
+    }
+
+    //
+    // try {
+    //     body
+    // } catch (Class name) {
+    //     ...
+    // }
+    //
+    // The dispatch block compares the selector of the landing pad with the
+    // type id of each catch clause in order.
+    //
+    pub fn compile_try(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        body: &[Stmt],
+        catches: &[Catch],
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let type_infos: Vec<_> = catches
+            .iter()
+            .map(|catch| self.type_info(&catch.class))
+            .collect();
+
+        self.push_handler(&type_infos);
+        self.compile_block(env, body)?;
+        let mut try_end_block = None;
+        if !self.is_terminated() {
+            let block = *try_end_block
+                .get_or_insert_with(|| self.context.append_basic_block(func, "try_end"));
+            self.builder.build_unconditional_branch(block);
+        }
+        let dispatch_block = self.pop_handler();
+
+        if let Some(dispatch_block) = dispatch_block {
+            self.builder.position_at_end(dispatch_block);
+            let (_, selector_slot) = self.exception_slots();
+            let selector = self
+                .builder
+                .build_load(self.i32_type, selector_slot, "selector")
+                .into_int_value();
+
+            let type_id_fn_type = self.i32_type.fn_type(&[self.ptr_type().into()], false);
+            for (catch, type_info) in catches.iter().zip(type_infos) {
+                let type_id = self
+                    .call_runtime("llvm.eh.typeid.for", type_id_fn_type, &[type_info.into()])
+                    .unwrap()
+                    .into_int_value();
+                let matches =
+                    self.builder
+                        .build_int_compare(IntPredicate::EQ, selector, type_id, "matches");
+                let catch_block = self.context.append_basic_block(func, "catch");
+                let next_block = self.context.append_basic_block(func, "next_catch");
+                self.builder
+                    .build_conditional_branch(matches, catch_block, next_block);
+
+                // Generate code for catch block
+                self.builder.position_at_end(catch_block);
+                let object = self.begin_catch(self.load_exception());
+                let value = self.builder.build_load(self.i32_type, object, &catch.name);
+                let ptr = self.build_entry_alloca(self.i32_type, &catch.name);
+                self.builder.build_store(ptr, value);
+                let catch_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
+                catch_env.borrow_mut().add(
+                    &catch.name,
+                    Pointer {
+                        ptr,
+                        data_type: DataType::Number,
+                    },
+                );
+
+                let mut labels = Vec::new();
+                for stmt in &catch.body {
+                    stmt.labels(&mut labels);
+                }
+                self.active_catches.borrow_mut().push(ActiveCatch {
+                    handler_depth: self.handlers.borrow().len(),
+                    labels,
+                });
+                self.compile_stmts(&catch_env, &catch.body)?;
+                self.active_catches.borrow_mut().pop();
+
+                if !self.is_terminated() {
+                    self.end_catch();
+                    let block = *try_end_block
+                        .get_or_insert_with(|| self.context.append_basic_block(func, "try_end"));
+                    self.builder.build_unconditional_branch(block);
+                }
+
+                next_block
+                    .move_after(self.builder.get_insert_block().unwrap())
+                    .unwrap();
+                self.builder.position_at_end(next_block);
+            }
+            self.compile_unhandled();
+        }
+
+        // Generate code for try end block
+        if let Some(try_end_block) = try_end_block {
+            try_end_block
+                .move_after(self.builder.get_insert_block().unwrap())
+                .unwrap();
+            self.builder.position_at_end(try_end_block);
+        }
+        Ok(())
+    }
+
+    //
+    // Prints a message and makes main exit with 1. Only exceptions none of the
+    // try regions in main caught reach this block.
+    //
+    pub fn compile_uncaught(&self, dispatch_block: BasicBlock<'ctx>) -> Result<(), String> {
+        self.builder.position_at_end(dispatch_block);
+        self.begin_catch(self.load_exception());
+        let env = Rc::new(RefCell::new(Env::new(None)));
+        self.compile_stmt(&env, &print("uncaught exception\n", vec![]))?;
+        self.end_catch();
+        self.builder
+            .build_return(Some(&self.i32_type.const_int(1, false)));
+        Ok(())
+    }
+}
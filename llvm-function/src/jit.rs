@@ -0,0 +1,141 @@
+use crate::ast::*;
+use crate::{Compiler, MAIN_FUNC_NAME};
+
+use inkwell::targets::{InitializationConfig, Target};
+use inkwell::values::FunctionValue;
+use inkwell::OptimizationLevel;
+use std::os::raw::c_char;
+
+// A Rust type a host function can take or return, with its source language
+// type
+pub trait HostType {
+    fn host_type() -> Type;
+}
+
+impl HostType for i32 {
+    fn host_type() -> Type {
+        Type::Int
+    }
+}
+
+impl HostType for f64 {
+    fn host_type() -> Type {
+        Type::Double
+    }
+}
+
+impl HostType for *const c_char {
+    fn host_type() -> Type {
+        Type::Str
+    }
+}
+
+impl HostType for () {
+    fn host_type() -> Type {
+        Type::Void
+    }
+}
+
+// An extern "C" fn whose signature is known from its Rust type
+pub trait HostFn: Copy {
+    fn signature() -> Type;
+    fn address(self) -> usize;
+}
+
+macro_rules! host_fn {
+    ($($arg:ident),*) => {
+        impl<R: HostType, $($arg: HostType),*> HostFn for extern "C" fn($($arg),*) -> R {
+            fn signature() -> Type {
+                function_ptr(vec![$($arg::host_type()),*], R::host_type())
+            }
+
+            fn address(self) -> usize {
+                self as usize
+            }
+        }
+    };
+}
+
+host_fn!();
+host_fn!(A);
+host_fn!(A, B);
+host_fn!(A, B, C);
+host_fn!(A, B, C, D);
+
+// A Rust function standing in for the extern declaration of the same name
+pub struct HostFunction {
+    pub name: String,
+    pub signature: Type,
+    pub address: usize,
+}
+
+impl HostFunction {
+    pub fn new<F: HostFn>(name: &str, host_fn: F) -> Self {
+        Self {
+            name: name.to_string(),
+            signature: F::signature(),
+            address: host_fn.address(),
+        }
+    }
+}
+
+//
+// Runs main in-process. Calls to an extern function registered as a host
+// function jump straight into the Rust code:
+//
+//   extern void log_value(int value);
+//
+// is served by
+//
+//   extern "C" fn log_value(value: i32) { ... }
+//
+// once registered with HostFunction::new("log_value", log_value as extern "C" fn(i32)).
+//
+impl<'ctx> Compiler<'ctx> {
+    fn check_host_function(&self, host: &HostFunction) -> Result<FunctionValue<'ctx>, String> {
+        let entry = self.functions.borrow().unique(&host.name)?;
+        if !entry.is_extern {
+            return Err(format!(
+                "host function {} is not declared extern",
+                host.name
+            ));
+        }
+        if entry.signature != host.signature {
+            return Err(format!(
+                "host function {} does not match its declaration: expected {:?}, got {:?}",
+                host.name, entry.signature, host.signature
+            ));
+        }
+        // Also rules out variadic declarations
+        if entry.func.get_type() != self.llvm_function_type(&host.signature, false) {
+            return Err(format!(
+                "host function {} does not match the LLVM type of its declaration",
+                host.name
+            ));
+        }
+        Ok(entry.func)
+    }
+
+    pub fn run_jit(&self, host_functions: &[HostFunction]) -> Result<i32, String> {
+        Target::initialize_native(&InitializationConfig::default())?;
+        let engine = self
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|e| e.to_string())?;
+        // Programs need not use every host function
+        for host in host_functions {
+            if self.functions.borrow().overloads(&host.name).is_empty() {
+                continue;
+            }
+            let func = self.check_host_function(host)?;
+            engine.add_global_mapping(&func, host.address);
+        }
+
+        unsafe {
+            let main = engine
+                .get_function::<unsafe extern "C" fn() -> i32>(MAIN_FUNC_NAME)
+                .map_err(|e| e.to_string())?;
+            Ok(main.call())
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use crate::ast::*;
+use crate::{Compiler, MAIN_FUNC_NAME};
+
+use inkwell::module::Linkage;
+use inkwell::targets::{
+    CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine,
+};
+use inkwell::OptimizationLevel;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+//
+// A program compiled as a library exports its functions that are neither
+// static nor extern, main aside. Next to lib<name>.so the
+// library comes with a C header <name>.h and a Rust binding module <name>.rs:
+//
+//   (int, int) divmod(int a, int b);
+//
+// is declared as
+//
+//   struct tuple2 { int _0; int _1; };
+//   struct tuple2 divmod(int a, int b);
+//
+// and
+//
+//   #[repr(C)]
+//   pub struct Tuple2 { pub _0: i32, pub _1: i32 }
+//
+//   #[link(name = "<name>")]
+//   extern "C" {
+//       pub fn divmod(a: i32, b: i32) -> Tuple2;
+//   }
+//
+// Overloads are exported under their mangled names, _Z5scalei and _Z5scaled
+// for int scale(int x) and double scale(double x).
+//
+// The declarations are written from the functions of the program rather than
+// the LLVM function types, which no longer tell a char * from a function
+// pointer, the C return type of a tuple from the integers it is passed in,
+// or carry the parameter names. The LLVM type each function would get from
+// its declaration is still compared with the one it was compiled with, so
+// that the header cannot drift from the library.
+//
+impl<'ctx> Compiler<'ctx> {
+    pub fn write_library(
+        &self,
+        functions: &[Function],
+        name: &str,
+        out_dir: &Path,
+    ) -> Result<(), String> {
+        // With the symbol each one is exported under
+        let mut exported: Vec<(&Function, String)> = Vec::new();
+        for function in functions {
+            if function.name == MAIN_FUNC_NAME {
+                continue;
+            }
+            let func = self
+                .functions
+                .borrow()
+                .overloads(&function.name)
+                .into_iter()
+                .find(|entry| entry.signature == function.signature())
+                .ok_or_else(|| format!("no declaration for {}", function.name))?
+                .func;
+            if func.get_type() != self.function_type(function)? {
+                return Err(format!(
+                    "declaration of {} does not match its LLVM type",
+                    function.name
+                ));
+            }
+            if func.count_basic_blocks() > 0 && func.get_linkage() == Linkage::External {
+                exported.push((function, func.get_name().to_string_lossy().to_string()));
+            }
+        }
+
+        std::fs::create_dir_all(out_dir)
+            .map_err(|e| format!("cannot create {}: {e}", out_dir.display()))?;
+        let path = |file: String| -> PathBuf { out_dir.join(file) };
+
+        let object = path(format!("{name}.o"));
+        self.write_object(&object)?;
+        let library = path(format!("lib{name}.so"));
+        let status = Command::new("cc")
+            .arg("-shared")
+            .arg("-o")
+            .arg(&library)
+            .arg(&object)
+            .args(["-lstdc++", "-lm"])
+            .status()
+            .map_err(|e| format!("cannot run cc: {e}"))?;
+        if !status.success() {
+            return Err(format!("linking {} failed", library.display()));
+        }
+
+        let source = self.module.get_name().to_string_lossy().to_string();
+        std::fs::write(
+            path(format!("{name}.h")),
+            c_header(name, &source, &exported),
+        )
+        .map_err(|e| e.to_string())?;
+        std::fs::write(
+            path(format!("{name}.rs")),
+            rust_bindings(name, &source, &exported),
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    // Position independent code for the host, to be linked into a shared
+    // library
+    fn write_object(&self, path: &Path) -> Result<(), String> {
+        Target::initialize_native(&InitializationConfig::default())?;
+        let triple = TargetMachine::get_default_triple();
+        let target = Target::from_triple(&triple).map_err(|e| e.to_string())?;
+        let target_machine = target
+            .create_target_machine(
+                &triple,
+                &TargetMachine::get_host_cpu_name().to_string(),
+                &TargetMachine::get_host_cpu_features().to_string(),
+                OptimizationLevel::Default,
+                RelocMode::PIC,
+                CodeModel::Default,
+            )
+            .ok_or_else(|| "cannot create a target machine for the host".to_string())?;
+        target_machine
+            .write_to_file(&self.module, FileType::Object, path)
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Every tuple is a struct of ints named after its size
+fn tuple_sizes(functions: &[(&Function, String)]) -> BTreeSet<usize> {
+    let mut sizes = BTreeSet::new();
+    let mut collect = |ty: &Type| {
+        if let Type::Tuple(elements) = ty {
+            sizes.insert(elements.len());
+        }
+    };
+    for (function, _) in functions {
+        collect(&function.return_type);
+        function
+            .params
+            .iter()
+            .for_each(|param| collect(&param.param_type));
+    }
+    sizes
+}
+
+// The C declaration of name, inside out for function pointers:
+// int (*f(int x))(int) is a function f returning int (*)(int)
+fn c_declaration(ty: &Type, name: &str) -> String {
+    let space = if name.is_empty() { "" } else { " " };
+    match ty {
+        Type::Int => format!("int{space}{name}"),
+        Type::Double => format!("double{space}{name}"),
+        Type::Str => format!("char *{name}"),
+        Type::Void => format!("void{space}{name}"),
+        Type::Tuple(elements) => format!("struct tuple{}{}{}", elements.len(), space, name),
+        Type::Function {
+            params,
+            return_type,
+        } => {
+            let params: Vec<String> = params
+                .iter()
+                .map(|param| c_declaration(param, ""))
+                .collect();
+            c_declaration(return_type, &format!("(*{})({})", name, params.join(", ")))
+        }
+    }
+}
+
+fn c_header(name: &str, source: &str, functions: &[(&Function, String)]) -> String {
+    let guard = format!("{}_H", name.to_uppercase());
+    let mut header = format!("// Generated from {source}.ll\n#ifndef {guard}\n#define {guard}\n\n");
+    for size in tuple_sizes(functions) {
+        let fields: Vec<String> = (0..size).map(|i| format!("int _{i};")).collect();
+        header += &format!("struct tuple{} {{ {} }};\n", size, fields.join(" "));
+    }
+    for (function, symbol) in functions {
+        let mut params: Vec<String> = function
+            .params
+            .iter()
+            .map(|param| c_declaration(&param.param_type, &param.name))
+            .collect();
+        if function.variadic {
+            params.push("...".to_string());
+        } else if params.is_empty() {
+            params.push("void".to_string());
+        }
+        let declarator = format!("{}({})", symbol, params.join(", "));
+        header += &format!("{};\n", c_declaration(&function.return_type, &declarator));
+    }
+    header + &format!("\n#endif // {guard}\n")
+}
+
+// Parameters and functions named after a Rust keyword become raw identifiers,
+// the few keywords that cannot be raw get a trailing underscore instead
+fn rust_identifier(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do",
+        "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let",
+        "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+        "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+        "virtual", "where", "while", "yield",
+    ];
+    match name {
+        "self" | "Self" | "super" | "crate" => format!("{name}_"),
+        _ if KEYWORDS.contains(&name) => format!("r#{name}"),
+        _ => name.to_string(),
+    }
+}
+
+fn rust_type(ty: &Type) -> String {
+    match ty {
+        Type::Int => "i32".to_string(),
+        Type::Double => "f64".to_string(),
+        Type::Str => "*const std::os::raw::c_char".to_string(),
+        Type::Void => "()".to_string(),
+        Type::Tuple(elements) => format!("Tuple{}", elements.len()),
+        Type::Function {
+            params,
+            return_type,
+        } => {
+            let params: Vec<String> = params.iter().map(rust_type).collect();
+            format!(
+                "extern \"C\" fn({}){}",
+                params.join(", "),
+                rust_return_type(return_type)
+            )
+        }
+    }
+}
+
+fn rust_return_type(ty: &Type) -> String {
+    match ty {
+        Type::Void => String::new(),
+        _ => format!(" -> {}", rust_type(ty)),
+    }
+}
+
+fn rust_bindings(name: &str, source: &str, functions: &[(&Function, String)]) -> String {
+    let mut bindings = format!("// Generated from {source}.ll\n");
+    for size in tuple_sizes(functions) {
+        let fields: Vec<String> = (0..size).map(|i| format!("pub _{i}: i32")).collect();
+        bindings += &format!(
+            "\n#[repr(C)]\n#[derive(Debug, Clone, Copy)]\npub struct Tuple{} {{\n    {},\n}}\n",
+            size,
+            fields.join(",\n    ")
+        );
+    }
+    bindings += &format!("\n#[link(name = \"{name}\")]\nextern \"C\" {{\n");
+    for (function, symbol) in functions {
+        let mut params: Vec<String> = function
+            .params
+            .iter()
+            .map(|param| {
+                format!(
+                    "{}: {}",
+                    rust_identifier(&param.name),
+                    rust_type(&param.param_type)
+                )
+            })
+            .collect();
+        if function.variadic {
+            params.push("...".to_string());
+        }
+        bindings += &format!(
+            "    pub fn {}({}){};\n",
+            rust_identifier(symbol),
+            params.join(", "),
+            rust_return_type(&function.return_type)
+        );
+    }
+    bindings + "}\n"
+}
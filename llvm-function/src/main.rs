@@ -1,14 +1,65 @@
+mod annotation;
+mod ast;
+mod closure;
+mod codegen;
+mod env;
+mod exception;
+mod jit;
+mod library;
+mod tail_recursion;
+mod tuple;
+use crate::ast::*;
+use crate::env::{DataType, FunctionTable};
+use crate::jit::HostFunction;
+use crate::tail_recursion::eliminate_tail_recursion;
 use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
 
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::module::Module;
-use inkwell::types::IntType;
-use inkwell::values::FunctionValue;
+use inkwell::types::{IntType, StructType};
+use inkwell::values::{BasicValueEnum, FunctionValue, PointerValue};
 use inkwell::AddressSpace;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 
 const MAIN_FUNC_NAME: &str = "main";
 
+// The block a goto label maps to, created on its first reference
+pub struct Label<'ctx> {
+    pub block: BasicBlock<'ctx>,
+    pub defined: bool,
+}
+
+// A try region, calls inside it are lowered to invokes that unwind to its
+// landing pad
+pub struct Handler<'ctx> {
+    // Type infos caught by this or an enclosing handler, null catches anything
+    pub clauses: Vec<BasicValueEnum<'ctx>>,
+    pub landing_pad: Option<BasicBlock<'ctx>>,
+    // Where exceptions not caught by an inner handler continue
+    pub dispatch: Option<BasicBlock<'ctx>>,
+}
+
+// A catch body being compiled
+pub struct ActiveCatch {
+    // Number of try regions around the try it belongs to
+    pub handler_depth: usize,
+    // Labels defined in the body
+    pub labels: Vec<String>,
+}
+
+// A lambda whose body is compiled once the enclosing function is done
+pub struct Lambda<'ctx> {
+    pub func: FunctionValue<'ctx>,
+    pub function: Function,
+    // Captured variables, in the order of the environment struct fields
+    pub captures: Vec<(String, DataType)>,
+    pub env_type: StructType<'ctx>,
+}
+
 pub struct Compiler<'ctx> {
     pub context: &'ctx Context,
     pub builder: Builder<'ctx>,
@@ -17,6 +68,27 @@ pub struct Compiler<'ctx> {
 
     pub main_func: FunctionValue<'ctx>,
     pub printf_func: FunctionValue<'ctx>,
+
+    // Labels of the function being compiled
+    pub labels: RefCell<HashMap<String, Label<'ctx>>>,
+    // Return type of the function being compiled
+    pub return_type: RefCell<Type>,
+    // Try regions of the function being compiled, innermost last
+    pub handlers: RefCell<Vec<Handler<'ctx>>>,
+    // Exception pointer and selector saved by the landing pads
+    pub exception_slots: Cell<Option<(PointerValue<'ctx>, PointerValue<'ctx>)>>,
+    // Catch bodies being compiled, innermost last. Every one is ended before a
+    // return, before a goto to a label outside it, and before a throw out of it.
+    pub active_catches: RefCell<Vec<ActiveCatch>>,
+    // va_list of the variadic function being compiled
+    pub va_list: Cell<Option<PointerValue<'ctx>>>,
+    // Where the function being compiled stores a tuple returned in memory
+    pub sret: Cell<Option<PointerValue<'ctx>>>,
+
+    // Every function of the program, by name
+    pub functions: RefCell<FunctionTable<'ctx>>,
+    pub lambdas: RefCell<Vec<Lambda<'ctx>>>,
+    pub lambda_count: Cell<usize>,
 }
 
 impl<'ctx> Compiler<'ctx> {
@@ -38,98 +110,2106 @@ impl<'ctx> Compiler<'ctx> {
             i32_type,
             main_func,
             printf_func,
+            labels: RefCell::new(HashMap::new()),
+            return_type: RefCell::new(Type::Int),
+            handlers: RefCell::new(Vec::new()),
+            exception_slots: Cell::new(None),
+            active_catches: RefCell::new(Vec::new()),
+            va_list: Cell::new(None),
+            sret: Cell::new(None),
+            functions: RefCell::new(FunctionTable::default()),
+            lambdas: RefCell::new(Vec::new()),
+            lambda_count: Cell::new(0),
         }
     }
 }
 
+//
+// int sign(int a) {
+//     if (a > 0) {
+//         return 1;
+//     }
+//     if (a < 0) {
+//         return 0 - 1;
+//         printf("unreachable\n");
+//     }
+//     return 0;
+// }
+//
+// int first_multiple_above(int n, int limit) {
+//     int i = 1;
+//     while (i < 100) {
+//         if (i * n > limit) {
+//             return i * n;
+//         }
+//         i = i + 1;
+//     }
+//     return 0;
+// }
 //
 // int sum(int a, int b) {
 //     return a + b;
 // }
 //
+// void show(int a) {
+//     printf("%d\n", a);
+// }
+//
 // int main() {
 //     int a = 10;
-//     int b = 0;
+//     int b = 20;
 //     int c = sum(a, b);
+//     show(c);
+//     show(sign(0 - 5));
+//     show(first_multiple_above(7, 30));
+// }
+//
+// Prints 30, -1 and 35. show and main fall off the end and get an implicit ret.
+//
+
+fn program() -> Vec<Function> {
+    vec![
+        function(
+            "sign",
+            vec![param("a", Type::Int)],
+            Type::Int,
+            vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Gt, var("a"), num(0)),
+                    then_body: vec![ret(num(1))],
+                    else_body: vec![],
+                },
+                Stmt::If {
+                    cond: binary(BinaryOp::Lt, var("a"), num(0)),
+                    then_body: vec![
+                        ret(binary(BinaryOp::Sub, num(0), num(1))),
+                        print("unreachable\n", vec![]),
+                    ],
+                    else_body: vec![],
+                },
+                ret(num(0)),
+            ],
+        ),
+        function(
+            "first_multiple_above",
+            vec![param("n", Type::Int), param("limit", Type::Int)],
+            Type::Int,
+            vec![
+                declare("i", num(1)),
+                Stmt::While {
+                    cond: binary(BinaryOp::Lt, var("i"), num(100)),
+                    body: vec![
+                        Stmt::If {
+                            cond: binary(
+                                BinaryOp::Gt,
+                                binary(BinaryOp::Mul, var("i"), var("n")),
+                                var("limit"),
+                            ),
+                            then_body: vec![ret(binary(BinaryOp::Mul, var("i"), var("n")))],
+                            else_body: vec![],
+                        },
+                        assign("i", binary(BinaryOp::Add, var("i"), num(1))),
+                    ],
+                },
+                ret(num(0)),
+            ],
+        ),
+        function(
+            "sum",
+            vec![param("a", Type::Int), param("b", Type::Int)],
+            Type::Int,
+            vec![ret(binary(BinaryOp::Add, var("a"), var("b")))],
+        ),
+        function(
+            "show",
+            vec![param("a", Type::Int)],
+            Type::Void,
+            vec![print("%d\n", vec![var("a")])],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                declare("a", num(10)),
+                declare("b", num(20)),
+                declare("c", call("sum", vec![var("a"), var("b")])),
+                Stmt::Expr(call("show", vec![var("c")])),
+                Stmt::Expr(call(
+                    "show",
+                    vec![call("sign", vec![binary(BinaryOp::Sub, num(0), num(5))])],
+                )),
+                Stmt::Expr(call(
+                    "show",
+                    vec![call("first_multiple_above", vec![num(7), num(30)])],
+                )),
+            ],
+        ),
+    ]
+}
+
+//
+// int positive(int a) {
+//     if (a > 0) {
+//         return 1;
+//     }
+// }
+//
+// int main() {
+//     return positive(1);
+// }
+//
+// Fails with "missing return in function positive"
+//
+
+fn missing_return_program() -> Vec<Function> {
+    vec![
+        function(
+            "positive",
+            vec![param("a", Type::Int)],
+            Type::Int,
+            vec![Stmt::If {
+                cond: binary(BinaryOp::Gt, var("a"), num(0)),
+                then_body: vec![ret(num(1))],
+                else_body: vec![],
+            }],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![ret(call("positive", vec![num(1)]))],
+        ),
+    ]
+}
+
+//
+// int count_down(int n) {
+//     int steps = 0;
+// again:
+//     if (n == 0) {
+//         goto done;
+//     }
+//     n = n - 1;
+//     steps = steps + 1;
+//     goto again;
+//     printf("unreachable\n");
+// done:
+//     return steps;
+// }
+//
+// int main() {
+//     return count_down(5);
+// }
+//
+// Exits with 5, "again" is a backward jump and "done" a forward one
+//
+
+fn goto_program() -> Vec<Function> {
+    vec![
+        function(
+            "count_down",
+            vec![param("n", Type::Int)],
+            Type::Int,
+            vec![
+                declare("steps", num(0)),
+                label("again"),
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("n"), num(0)),
+                    then_body: vec![goto("done")],
+                    else_body: vec![],
+                },
+                assign("n", binary(BinaryOp::Sub, var("n"), num(1))),
+                assign("steps", binary(BinaryOp::Add, var("steps"), num(1))),
+                goto("again"),
+                print("unreachable\n", vec![]),
+                label("done"),
+                ret(var("steps")),
+            ],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![ret(call("count_down", vec![num(5)]))],
+        ),
+    ]
+}
+
+//
+// int main() {
+//     goto end;
+// }
+//
+// Fails with "undefined label end in function main"
+//
+
+fn undefined_label_program() -> Vec<Function> {
+    vec![function(
+        MAIN_FUNC_NAME,
+        vec![],
+        Type::Int,
+        vec![goto("end")],
+    )]
+}
+
+//
+// int check(int a) {
+//     if (a < 0) {
+//         throw Negative(a);
+//     }
+//     if (a == 0) {
+//         throw Zero(a);
+//     }
+//     return a * 5;
+// }
+//
+// int safe_check(int a) {
+//     try {
+//         return check(a);
+//     } catch (Negative n) {
+//         printf("caught negative %d\n", n);
+//         return 0;
+//     }
+// }
+//
+// int main() {
+//     printf("%d\n", safe_check(1));
+//     try {
+//         printf("%d\n", check(20));
+//         printf("%d\n", safe_check(0 - 3));
+//         throw Zero(7);
+//     } catch (Negative n) {
+//         printf("caught negative %d\n", n);
+//     } catch (Zero z) {
+//         printf("caught zero %d\n", z);
+//         goto checked;  /* ends the catch before leaving it */
+//     }
+// checked:
+//     check(0);
+// }
+//
+// Prints 5, 100, "caught negative -3", 0 and "caught zero 7". Zero thrown by
+// the last check is not caught, so main prints "uncaught exception" and exits
+// with 1.
+//
+
+fn exceptions_program() -> Vec<Function> {
+    vec![
+        function(
+            "check",
+            vec![param("a", Type::Int)],
+            Type::Int,
+            vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Lt, var("a"), num(0)),
+                    then_body: vec![throw("Negative", var("a"))],
+                    else_body: vec![],
+                },
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("a"), num(0)),
+                    then_body: vec![throw("Zero", var("a"))],
+                    else_body: vec![],
+                },
+                ret(binary(BinaryOp::Mul, var("a"), num(5))),
+            ],
+        ),
+        function(
+            "safe_check",
+            vec![param("a", Type::Int)],
+            Type::Int,
+            vec![Stmt::Try {
+                body: vec![ret(call("check", vec![var("a")]))],
+                catches: vec![catch(
+                    "Negative",
+                    "n",
+                    vec![print("caught negative %d\n", vec![var("n")]), ret(num(0))],
+                )],
+            }],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                print("%d\n", vec![call("safe_check", vec![num(1)])]),
+                Stmt::Try {
+                    body: vec![
+                        print("%d\n", vec![call("check", vec![num(20)])]),
+                        print(
+                            "%d\n",
+                            vec![call(
+                                "safe_check",
+                                vec![binary(BinaryOp::Sub, num(0), num(3))],
+                            )],
+                        ),
+                        throw("Zero", num(7)),
+                    ],
+                    catches: vec![
+                        catch(
+                            "Negative",
+                            "n",
+                            vec![print("caught negative %d\n", vec![var("n")])],
+                        ),
+                        catch(
+                            "Zero",
+                            "z",
+                            vec![print("caught zero %d\n", vec![var("z")]), goto("checked")],
+                        ),
+                    ],
+                },
+                label("checked"),
+                Stmt::Expr(call("check", vec![num(0)])),
+            ],
+        ),
+    ]
+}
+
+//
+// int main() {
+//     printf("%d\n", factorial(5));
+//     printf("%d\n", fibonacci(10));
+//     printf("%d\n", is_even(10));
+//     return is_odd(7);
+// }
+//
+// int factorial(int n) {
+//     if (n < 2) {
+//         return 1;
+//     }
+//     return n * factorial(n - 1);
+// }
+//
+// int fibonacci(int n) {
+//     if (n < 2) {
+//         return n;
+//     }
+//     return fibonacci(n - 1) + fibonacci(n - 2);
+// }
+//
+// int is_even(int n) {
+//     if (n == 0) {
+//         return 1;
+//     }
+//     return is_odd(n - 1);
+// }
+//
+// int is_odd(int n) {
+//     if (n == 0) {
+//         return 0;
+//     }
+//     return is_even(n - 1);
+// }
+//
+// Prints 120, 55 and 1, and exits with 1. main calls functions defined after
+// it, is_even and is_odd are mutually recursive.
+//
+
+fn recursion_program() -> Vec<Function> {
+    vec![
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                print("%d\n", vec![call("factorial", vec![num(5)])]),
+                print("%d\n", vec![call("fibonacci", vec![num(10)])]),
+                print("%d\n", vec![call("is_even", vec![num(10)])]),
+                ret(call("is_odd", vec![num(7)])),
+            ],
+        ),
+        function(
+            "factorial",
+            vec![param("n", Type::Int)],
+            Type::Int,
+            vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Lt, var("n"), num(2)),
+                    then_body: vec![ret(num(1))],
+                    else_body: vec![],
+                },
+                ret(binary(
+                    BinaryOp::Mul,
+                    var("n"),
+                    call("factorial", vec![binary(BinaryOp::Sub, var("n"), num(1))]),
+                )),
+            ],
+        ),
+        function(
+            "fibonacci",
+            vec![param("n", Type::Int)],
+            Type::Int,
+            vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Lt, var("n"), num(2)),
+                    then_body: vec![ret(var("n"))],
+                    else_body: vec![],
+                },
+                ret(binary(
+                    BinaryOp::Add,
+                    call("fibonacci", vec![binary(BinaryOp::Sub, var("n"), num(1))]),
+                    call("fibonacci", vec![binary(BinaryOp::Sub, var("n"), num(2))]),
+                )),
+            ],
+        ),
+        function(
+            "is_even",
+            vec![param("n", Type::Int)],
+            Type::Int,
+            vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("n"), num(0)),
+                    then_body: vec![ret(num(1))],
+                    else_body: vec![],
+                },
+                ret(call(
+                    "is_odd",
+                    vec![binary(BinaryOp::Sub, var("n"), num(1))],
+                )),
+            ],
+        ),
+        function(
+            "is_odd",
+            vec![param("n", Type::Int)],
+            Type::Int,
+            vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("n"), num(0)),
+                    then_body: vec![ret(num(0))],
+                    else_body: vec![],
+                },
+                ret(call(
+                    "is_even",
+                    vec![binary(BinaryOp::Sub, var("n"), num(1))],
+                )),
+            ],
+        ),
+    ]
+}
+
+//
+// int main() {
+//     return missing(1);
+// }
+//
+// Fails with "undefined function missing"
+//
+
+fn undefined_function_program() -> Vec<Function> {
+    vec![function(
+        MAIN_FUNC_NAME,
+        vec![],
+        Type::Int,
+        vec![ret(call("missing", vec![num(1)]))],
+    )]
+}
+
+//
+// int sum_all(int n, ...) {
+//     int total = 0;
+//     while (n > 0) {
+//         total = total + va_arg(int);
+//         n = n - 1;
+//     }
+//     return total;
+// }
+//
+// int main() {
+//     printf("%d\n", sum_all(3, 10, 20, 30));
+//     printf("%d\n", sum_all(0));
+//     return sum_all(2, 4, 5);
+// }
+//
+// Prints 60 and 0, and exits with 9. sum_all follows the C calling
+// convention for varargs, so C code can call it as well.
+//
+
+fn variadic_program() -> Vec<Function> {
+    vec![
+        variadic_function(
+            "sum_all",
+            vec![param("n", Type::Int)],
+            Type::Int,
+            vec![
+                declare("total", num(0)),
+                Stmt::While {
+                    cond: binary(BinaryOp::Gt, var("n"), num(0)),
+                    body: vec![
+                        assign("total", binary(BinaryOp::Add, var("total"), Expr::VaArg)),
+                        assign("n", binary(BinaryOp::Sub, var("n"), num(1))),
+                    ],
+                },
+                ret(var("total")),
+            ],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                print(
+                    "%d\n",
+                    vec![call("sum_all", vec![num(3), num(10), num(20), num(30)])],
+                ),
+                print("%d\n", vec![call("sum_all", vec![num(0)])]),
+                ret(call("sum_all", vec![num(2), num(4), num(5)])),
+            ],
+        ),
+    ]
+}
+
+//
+// int main() {
+//     int base = 10;
+//     int step = 5;
+//     auto add_base = [=](int x) -> int { return x + base; };
+//     printf("%d\n", add_base(1));
+//     base = 100;
+//     printf("%d\n", add_base(1));
+//
+//     auto scale = [=](int x) -> int {
+//         auto add_step = [=](int y) -> int { return y + step; };
+//         return add_step(x) * step;
+//     };
+//     auto alias = scale;
+//     auto show = [](int x) -> void { printf("%d\n", x); };
+//     show(alias(2));
+//
+//     auto show_half = [](double x) -> void { printf("%.1f\n", x * 0.5); };
+//     show_half(3);
+//     return add_base(7);
+// }
+//
+// Prints 11 twice, add_base keeps the copy of base it captured, then 35 and
+// 1.5, the int argument of show_half is converted to a double, and exits
+// with 17.
+//
+
+fn closures_program() -> Vec<Function> {
+    vec![function(
+        MAIN_FUNC_NAME,
+        vec![],
+        Type::Int,
+        vec![
+            declare("base", num(10)),
+            declare("step", num(5)),
+            declare(
+                "add_base",
+                lambda(
+                    vec![param("x", Type::Int)],
+                    Type::Int,
+                    vec![ret(binary(BinaryOp::Add, var("x"), var("base")))],
+                ),
+            ),
+            print("%d\n", vec![call("add_base", vec![num(1)])]),
+            assign("base", num(100)),
+            print("%d\n", vec![call("add_base", vec![num(1)])]),
+            declare(
+                "scale",
+                lambda(
+                    vec![param("x", Type::Int)],
+                    Type::Int,
+                    vec![
+                        declare(
+                            "add_step",
+                            lambda(
+                                vec![param("y", Type::Int)],
+                                Type::Int,
+                                vec![ret(binary(BinaryOp::Add, var("y"), var("step")))],
+                            ),
+                        ),
+                        ret(binary(
+                            BinaryOp::Mul,
+                            call("add_step", vec![var("x")]),
+                            var("step"),
+                        )),
+                    ],
+                ),
+            ),
+            declare("alias", var("scale")),
+            declare(
+                "show",
+                lambda(
+                    vec![param("x", Type::Int)],
+                    Type::Void,
+                    vec![print("%d\n", vec![var("x")])],
+                ),
+            ),
+            Stmt::Expr(call("show", vec![call("alias", vec![num(2)])])),
+            declare(
+                "show_half",
+                lambda(
+                    vec![param("x", Type::Double)],
+                    Type::Void,
+                    vec![print(
+                        "%.1f\n",
+                        vec![binary(BinaryOp::Mul, var("x"), double(0.5))],
+                    )],
+                ),
+            ),
+            Stmt::Expr(call("show_half", vec![num(3)])),
+            ret(call("add_base", vec![num(7)])),
+        ],
+    )]
+}
+
+//
+// int ascending(int a, int b) {
+//     return a - b;
+// }
+//
+// int descending(int a, int b) {
+//     return b - a;
+// }
+//
+// int pick(int a, int b, int (*compare)(int, int)) {
+//     if (compare(a, b) < 0) {
+//         return a;
+//     }
+//     return b;
+// }
+//
+// int (*order(int reverse))(int, int) {
+//     if (reverse) {
+//         return &descending;
+//     }
+//     return &ascending;
+// }
 //
-//     printf("%d\n", b);
+// int main() {
+//     int (*compare)(int, int) = &ascending;
+//     printf("%d\n", pick(3, 7, compare));
+//     compare = order(1);
+//     printf("%d\n", pick(3, 7, compare));
+//     printf("%d\n", pick(3, 7, order(0)));
+//     return compare(2, 9);
 // }
+//
+// Prints 3, 7 and 3, and exits with 7. The comparators are plain C function
+// pointers, a C function such as qsort could call them as well.
+//
 
+fn function_pointers_program() -> Vec<Function> {
+    let comparator = function_ptr(vec![Type::Int, Type::Int], Type::Int);
+    vec![
+        function(
+            "ascending",
+            vec![param("a", Type::Int), param("b", Type::Int)],
+            Type::Int,
+            vec![ret(binary(BinaryOp::Sub, var("a"), var("b")))],
+        ),
+        function(
+            "descending",
+            vec![param("a", Type::Int), param("b", Type::Int)],
+            Type::Int,
+            vec![ret(binary(BinaryOp::Sub, var("b"), var("a")))],
+        ),
+        function(
+            "pick",
+            vec![
+                param("a", Type::Int),
+                param("b", Type::Int),
+                param("compare", comparator.clone()),
+            ],
+            Type::Int,
+            vec![
+                Stmt::If {
+                    cond: binary(
+                        BinaryOp::Lt,
+                        call("compare", vec![var("a"), var("b")]),
+                        num(0),
+                    ),
+                    then_body: vec![ret(var("a"))],
+                    else_body: vec![],
+                },
+                ret(var("b")),
+            ],
+        ),
+        function(
+            "order",
+            vec![param("reverse", Type::Int)],
+            comparator,
+            vec![
+                Stmt::If {
+                    cond: var("reverse"),
+                    then_body: vec![ret(function_ref("descending"))],
+                    else_body: vec![],
+                },
+                ret(function_ref("ascending")),
+            ],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                declare("compare", function_ref("ascending")),
+                print(
+                    "%d\n",
+                    vec![call("pick", vec![num(3), num(7), var("compare")])],
+                ),
+                assign("compare", call("order", vec![num(1)])),
+                print(
+                    "%d\n",
+                    vec![call("pick", vec![num(3), num(7), var("compare")])],
+                ),
+                print(
+                    "%d\n",
+                    vec![call(
+                        "pick",
+                        vec![num(3), num(7), call("order", vec![num(0)])],
+                    )],
+                ),
+                ret(call("compare", vec![num(2), num(9)])),
+            ],
+        ),
+    ]
+}
+
+//
+// int negate(int a) {
+//     return 0 - a;
+// }
+//
+// int apply(int (*f)(int, int)) {
+//     return f(1, 2);
+// }
+//
+// int main() {
+//     return apply(&negate);
+// }
+//
+// Fails with "argument 1 of apply has the wrong type: FunctionRef(\"negate\")"
+//
+
+fn wrong_signature_program() -> Vec<Function> {
+    vec![
+        function(
+            "negate",
+            vec![param("a", Type::Int)],
+            Type::Int,
+            vec![ret(binary(BinaryOp::Sub, num(0), var("a")))],
+        ),
+        function(
+            "apply",
+            vec![param(
+                "f",
+                function_ptr(vec![Type::Int, Type::Int], Type::Int),
+            )],
+            Type::Int,
+            vec![ret(call("f", vec![num(1), num(2)]))],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![ret(call("apply", vec![function_ref("negate")]))],
+        ),
+    ]
+}
+
+//
+// int sum_all(int n, ...) {
+//     return n;
+// }
+//
+// int main() {
+//     int (*f)(int) = &sum_all;
+//     return f(1);
+// }
+//
+// Fails with "variadic function sum_all cannot be referenced"
+//
+
+fn variadic_ref_program() -> Vec<Function> {
+    vec![
+        variadic_function(
+            "sum_all",
+            vec![param("n", Type::Int)],
+            Type::Int,
+            vec![ret(var("n"))],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                declare("f", function_ref("sum_all")),
+                ret(call("f", vec![num(1)])),
+            ],
+        ),
+    ]
+}
+
+//
+// static int count_down(int n, int steps) {
+//     if (n == 0) {
+//         return steps;
+//     }
+//     return count_down(n - 1, steps + 1);
+// }
+//
+// static int is_even(int n) {
+//     if (n == 0) {
+//         return 1;
+//     }
+//     return is_odd(n - 1);
+// }
+//
+// static int is_odd(int n) {
+//     if (n == 0) {
+//         return 0;
+//     }
+//     return is_even(n - 1);
+// }
+//
+// int main() {
+//     printf("%d\n", count_down(1000000, 0));
+//     printf("%d\n", is_even(1000001));
+// }
+//
+// Prints 1000000 and 0 without overflowing the stack, even at -O0, as static
+// functions call each other with guaranteed tail calls. With
+// --eliminate-tail-recursion count_down becomes a loop, the mutual recursion
+// still relies on tail calls.
+//
+
+fn tail_calls_program() -> Vec<Function> {
+    vec![
+        annotate(
+            vec![Annotation::Static],
+            function(
+                "count_down",
+                vec![param("n", Type::Int), param("steps", Type::Int)],
+                Type::Int,
+                vec![
+                    Stmt::If {
+                        cond: binary(BinaryOp::Eq, var("n"), num(0)),
+                        then_body: vec![ret(var("steps"))],
+                        else_body: vec![],
+                    },
+                    ret(call(
+                        "count_down",
+                        vec![
+                            binary(BinaryOp::Sub, var("n"), num(1)),
+                            binary(BinaryOp::Add, var("steps"), num(1)),
+                        ],
+                    )),
+                ],
+            ),
+        ),
+        annotate(
+            vec![Annotation::Static],
+            function(
+                "is_even",
+                vec![param("n", Type::Int)],
+                Type::Int,
+                vec![
+                    Stmt::If {
+                        cond: binary(BinaryOp::Eq, var("n"), num(0)),
+                        then_body: vec![ret(num(1))],
+                        else_body: vec![],
+                    },
+                    ret(call(
+                        "is_odd",
+                        vec![binary(BinaryOp::Sub, var("n"), num(1))],
+                    )),
+                ],
+            ),
+        ),
+        annotate(
+            vec![Annotation::Static],
+            function(
+                "is_odd",
+                vec![param("n", Type::Int)],
+                Type::Int,
+                vec![
+                    Stmt::If {
+                        cond: binary(BinaryOp::Eq, var("n"), num(0)),
+                        then_body: vec![ret(num(0))],
+                        else_body: vec![],
+                    },
+                    ret(call(
+                        "is_even",
+                        vec![binary(BinaryOp::Sub, var("n"), num(1))],
+                    )),
+                ],
+            ),
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                print("%d\n", vec![call("count_down", vec![num(1000000), num(0)])]),
+                print("%d\n", vec![call("is_even", vec![num(1000001)])]),
+            ],
+        ),
+    ]
+}
+
+//
+// static inline int square(int x) {
+//     return x * x;
+// }
+//
+// static noinline pure int cube(int x) {
+//     return square(x) * x;
+// }
+//
+// pure int sum_to(int n) {
+//     int total = 0;
+//     int i = 1;
+//     while (i <= n) {
+//         total = total + i;
+//         i = i + 1;
+//     }
+//     return total;
+// }
+//
+// static int unused(int x) {
+//     return x + 1;
+// }
+//
+// int main() {
+//     printf("%d\n", square(7));
+//     printf("%d\n", cube(3));
+//     printf("%d\n", sum_to(10));
+//     return 0;
+// }
+//
+// Prints 49, 27 and 55. Optimized, square is inlined everywhere and, like
+// unused, removed from the module, cube is kept as a call.
+//
+
+fn annotations_program() -> Vec<Function> {
+    vec![
+        annotate(
+            vec![Annotation::Static, Annotation::Inline],
+            function(
+                "square",
+                vec![param("x", Type::Int)],
+                Type::Int,
+                vec![ret(binary(BinaryOp::Mul, var("x"), var("x")))],
+            ),
+        ),
+        annotate(
+            vec![Annotation::Static, Annotation::NoInline, Annotation::Pure],
+            function(
+                "cube",
+                vec![param("x", Type::Int)],
+                Type::Int,
+                vec![ret(binary(
+                    BinaryOp::Mul,
+                    call("square", vec![var("x")]),
+                    var("x"),
+                ))],
+            ),
+        ),
+        annotate(
+            vec![Annotation::Pure],
+            function(
+                "sum_to",
+                vec![param("n", Type::Int)],
+                Type::Int,
+                vec![
+                    declare("total", num(0)),
+                    declare("i", num(1)),
+                    Stmt::While {
+                        cond: binary(BinaryOp::Le, var("i"), var("n")),
+                        body: vec![
+                            assign("total", binary(BinaryOp::Add, var("total"), var("i"))),
+                            assign("i", binary(BinaryOp::Add, var("i"), num(1))),
+                        ],
+                    },
+                    ret(var("total")),
+                ],
+            ),
+        ),
+        annotate(
+            vec![Annotation::Static],
+            function(
+                "unused",
+                vec![param("x", Type::Int)],
+                Type::Int,
+                vec![ret(binary(BinaryOp::Add, var("x"), num(1)))],
+            ),
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                print("%d\n", vec![call("square", vec![num(7)])]),
+                print("%d\n", vec![call("cube", vec![num(3)])]),
+                print("%d\n", vec![call("sum_to", vec![num(10)])]),
+                ret(num(0)),
+            ],
+        ),
+    ]
+}
+
+//
+// pure int log_square(int x) {
+//     printf("%d\n", x);
+//     return x * x;
+// }
+//
+// int main() {
+//     return log_square(3);
+// }
+//
+// Fails to compile, log_square is not pure
+//
+
+fn impure_program() -> Vec<Function> {
+    vec![
+        annotate(
+            vec![Annotation::Pure],
+            function(
+                "log_square",
+                vec![param("x", Type::Int)],
+                Type::Int,
+                vec![
+                    print("%d\n", vec![var("x")]),
+                    ret(binary(BinaryOp::Mul, var("x"), var("x"))),
+                ],
+            ),
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![ret(call("log_square", vec![num(3)]))],
+        ),
+    ]
+}
+
+//
+// (int, int) divmod(int a, int b) {
+//     int quotient = 0;
+//     while (a >= b) {
+//         a = a - b;
+//         quotient = quotient + 1;
+//     }
+//     return (quotient, a);
+// }
+//
+// (int, int, int, int, int) multiples(int x) {
+//     return (x, x * 2, x * 3, x * 4, x * 5);
+// }
+//
+// int main() {
+//     auto [quotient, remainder] = divmod(17, 5);
+//     printf("%d %d\n", quotient, remainder);
+//     auto [a, b, c, d, e] = multiples(2);
+//     printf("%d %d %d %d %d\n", a, b, c, d, e);
+//     return quotient + remainder;
+// }
+//
+// Prints "3 2" and "2 4 6 8 10", then exits 5. divmod returns its pair
+// in a register, multiples through an sret pointer.
+//
+
+fn tuples_program() -> Vec<Function> {
+    vec![
+        function(
+            "divmod",
+            vec![param("a", Type::Int), param("b", Type::Int)],
+            Type::Tuple(vec![Type::Int, Type::Int]),
+            vec![
+                declare("quotient", num(0)),
+                Stmt::While {
+                    cond: binary(BinaryOp::Ge, var("a"), var("b")),
+                    body: vec![
+                        assign("a", binary(BinaryOp::Sub, var("a"), var("b"))),
+                        assign("quotient", binary(BinaryOp::Add, var("quotient"), num(1))),
+                    ],
+                },
+                ret(tuple(vec![var("quotient"), var("a")])),
+            ],
+        ),
+        function(
+            "multiples",
+            vec![param("x", Type::Int)],
+            Type::Tuple(vec![Type::Int; 5]),
+            vec![ret(tuple(vec![
+                var("x"),
+                binary(BinaryOp::Mul, var("x"), num(2)),
+                binary(BinaryOp::Mul, var("x"), num(3)),
+                binary(BinaryOp::Mul, var("x"), num(4)),
+                binary(BinaryOp::Mul, var("x"), num(5)),
+            ]))],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                destructure(
+                    &["quotient", "remainder"],
+                    call("divmod", vec![num(17), num(5)]),
+                ),
+                print("%d %d\n", vec![var("quotient"), var("remainder")]),
+                destructure(&["a", "b", "c", "d", "e"], call("multiples", vec![num(2)])),
+                print(
+                    "%d %d %d %d %d\n",
+                    vec![var("a"), var("b"), var("c"), var("d"), var("e")],
+                ),
+                ret(binary(BinaryOp::Add, var("quotient"), var("remainder"))),
+            ],
+        ),
+    ]
+}
+
+//
+// extern double sqrt(double x);
+// extern int puts(char *s);
+// extern int abs(int n);
+// extern int printf(char *format, ...);
+//
+// int main() {
+//     puts("hello from puts");
+//     double root = sqrt(2.0) * 2.0;
+//     printf("%f %s\n", root, "via printf");
+//     if (root > 2.5) {
+//         return abs(0 - 3);
+//     }
+//     return 0;
+// }
+//
+// Prints "hello from puts" and "2.828427 via printf", then exits 3. sqrt
+// comes from libm.
+//
+
+fn externs_program() -> Vec<Function> {
+    vec![
+        annotate(
+            vec![Annotation::Extern],
+            function("sqrt", vec![param("x", Type::Double)], Type::Double, vec![]),
+        ),
+        annotate(
+            vec![Annotation::Extern],
+            function("puts", vec![param("s", Type::Str)], Type::Int, vec![]),
+        ),
+        annotate(
+            vec![Annotation::Extern],
+            function("abs", vec![param("n", Type::Int)], Type::Int, vec![]),
+        ),
+        annotate(
+            vec![Annotation::Extern],
+            variadic_function(
+                "printf",
+                vec![param("format", Type::Str)],
+                Type::Int,
+                vec![],
+            ),
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                Stmt::Expr(call("puts", vec![string("hello from puts")])),
+                declare(
+                    "root",
+                    binary(BinaryOp::Mul, call("sqrt", vec![double(2.0)]), double(2.0)),
+                ),
+                Stmt::Expr(call(
+                    "printf",
+                    vec![string("%f %s\n"), var("root"), string("via printf")],
+                )),
+                Stmt::If {
+                    cond: binary(BinaryOp::Gt, var("root"), double(2.5)),
+                    then_body: vec![ret(call(
+                        "abs",
+                        vec![binary(BinaryOp::Sub, num(0), num(3))],
+                    ))],
+                    else_body: vec![],
+                },
+                ret(num(0)),
+            ],
+        ),
+    ]
+}
+
+//
+// static int square(int x) {
+//     return x * x;
+// }
+//
+// int sum_of_squares(int a, int b) {
+//     return square(a) + square(b);
+// }
+//
+// double scale(double x, double factor) {
+//     return x * factor;
+// }
+//
+// (int, int) minmax(int a, int b) {
+//     if (a < b) {
+//         return (a, b);
+//     }
+//     return (b, a);
+// }
+//
+// int apply(int (*f)(int, int), int x) {
+//     return f(x, x);
+// }
+//
+// A library without main, built with --library=kernels into libkernels.so
+// along with the kernels.h header and kernels.rs Rust bindings. From Rust,
+// unsafe { sum_of_squares(3, 4) } is 25 and unsafe { minmax(9, 2) } is
+// Tuple2 { _0: 2, _1: 9 }.
+//
+
+fn kernels_program() -> Vec<Function> {
+    vec![
+        annotate(
+            vec![Annotation::Static],
+            function(
+                "square",
+                vec![param("x", Type::Int)],
+                Type::Int,
+                vec![ret(binary(BinaryOp::Mul, var("x"), var("x")))],
+            ),
+        ),
+        function(
+            "sum_of_squares",
+            vec![param("a", Type::Int), param("b", Type::Int)],
+            Type::Int,
+            vec![ret(binary(
+                BinaryOp::Add,
+                call("square", vec![var("a")]),
+                call("square", vec![var("b")]),
+            ))],
+        ),
+        function(
+            "scale",
+            vec![param("x", Type::Double), param("factor", Type::Double)],
+            Type::Double,
+            vec![ret(binary(BinaryOp::Mul, var("x"), var("factor")))],
+        ),
+        function(
+            "minmax",
+            vec![param("a", Type::Int), param("b", Type::Int)],
+            Type::Tuple(vec![Type::Int, Type::Int]),
+            vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Lt, var("a"), var("b")),
+                    then_body: vec![ret(tuple(vec![var("a"), var("b")]))],
+                    else_body: vec![],
+                },
+                ret(tuple(vec![var("b"), var("a")])),
+            ],
+        ),
+        function(
+            "apply",
+            vec![
+                param("f", function_ptr(vec![Type::Int, Type::Int], Type::Int)),
+                param("x", Type::Int),
+            ],
+            Type::Int,
+            vec![ret(call("f", vec![var("x"), var("x")]))],
+        ),
+    ]
+}
+
+//
+// extern void log_value(int value);
+// extern int next_id();
+//
+// int main() {
+//     int a = next_id();
+//     int b = next_id();
+//     log_value(a + b * 10);
+//     return next_id();
+// }
+//
+// Run with --jit, log_value and next_id are the Rust functions below. Prints
+// "log: 21" and "main returned 3".
+//
+
+fn callbacks_program() -> Vec<Function> {
+    vec![
+        annotate(
+            vec![Annotation::Extern],
+            function(
+                "log_value",
+                vec![param("value", Type::Int)],
+                Type::Void,
+                vec![],
+            ),
+        ),
+        annotate(
+            vec![Annotation::Extern],
+            function("next_id", vec![], Type::Int, vec![]),
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                declare("a", call("next_id", vec![])),
+                declare("b", call("next_id", vec![])),
+                Stmt::Expr(call(
+                    "log_value",
+                    vec![binary(
+                        BinaryOp::Add,
+                        var("a"),
+                        binary(BinaryOp::Mul, var("b"), num(10)),
+                    )],
+                )),
+                ret(call("next_id", vec![])),
+            ],
+        ),
+    ]
+}
+
+//
+// extern void log_value(double value);
+//
+// int main() {
+//     log_value(1.5);
+//     return 0;
+// }
+//
+// Fails to run with --jit, the Rust log_value takes an int
+//
+
+fn wrong_callback_program() -> Vec<Function> {
+    vec![
+        annotate(
+            vec![Annotation::Extern],
+            function(
+                "log_value",
+                vec![param("value", Type::Double)],
+                Type::Void,
+                vec![],
+            ),
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                Stmt::Expr(call("log_value", vec![double(1.5)])),
+                ret(num(0)),
+            ],
+        ),
+    ]
+}
+
+//
+// void show(int x) {
+//     printf("int %d\n", x);
+// }
+//
+// void show(double x) {
+//     printf("double %f\n", x);
+// }
+//
+// double twice(double x) {
+//     return x * 2.0;
+// }
+//
+// int main() {
+//     show(7);
+//     show(2.5);
+//     show(twice(3));
+//     return 0;
+// }
+//
+// Prints "int 7", "double 2.500000" and "double 6.000000". show is emitted as
+// _Z4showi and _Z4showd, 3 is converted to a double for twice.
+//
+
+fn overloads_program() -> Vec<Function> {
+    vec![
+        function(
+            "show",
+            vec![param("x", Type::Int)],
+            Type::Void,
+            vec![print("int %d\n", vec![var("x")])],
+        ),
+        function(
+            "show",
+            vec![param("x", Type::Double)],
+            Type::Void,
+            vec![print("double %f\n", vec![var("x")])],
+        ),
+        function(
+            "twice",
+            vec![param("x", Type::Double)],
+            Type::Double,
+            vec![ret(binary(BinaryOp::Mul, var("x"), double(2.0)))],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![
+                Stmt::Expr(call("show", vec![num(7)])),
+                Stmt::Expr(call("show", vec![double(2.5)])),
+                Stmt::Expr(call("show", vec![call("twice", vec![num(3)])])),
+                ret(num(0)),
+            ],
+        ),
+    ]
+}
+
+//
+// int pick(int a, double b) {
+//     return 1;
+// }
+//
+// int pick(double a, int b) {
+//     return 2;
+// }
+//
+// int main() {
+//     return pick(1, 2);
+// }
+//
+// Fails to compile, both overloads need one conversion
+//
+
+fn ambiguous_program() -> Vec<Function> {
+    vec![
+        function(
+            "pick",
+            vec![param("a", Type::Int), param("b", Type::Double)],
+            Type::Int,
+            vec![ret(num(1))],
+        ),
+        function(
+            "pick",
+            vec![param("a", Type::Double), param("b", Type::Int)],
+            Type::Int,
+            vec![ret(num(2))],
+        ),
+        function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![ret(call("pick", vec![num(1), num(2)]))],
+        ),
+    ]
+}
+
+extern "C" fn log_value(value: i32) {
+    println!("log: {value}");
+}
+
+extern "C" fn next_id() -> i32 {
+    static NEXT_ID: AtomicI32 = AtomicI32::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Rust functions JIT compiled code can call
+fn host_functions() -> Vec<HostFunction> {
+    vec![
+        HostFunction::new("log_value", log_value as extern "C" fn(i32)),
+        HostFunction::new("next_id", next_id as extern "C" fn() -> i32),
+    ]
+}
+
+// Usage: cargo run --bin llvm-function [missing_return | goto | undefined_label | exceptions |
+//                                       recursion | undefined_function | variadic | closures |
+//                                       function_pointers | wrong_signature | variadic_ref |
+//                                       tail_calls | annotations | impure | tuples | externs |
+//                                       kernels | callbacks | wrong_callback | overloads |
+//                                       ambiguous]
+//                                      [--eliminate-tail-recursion] [--jit]
+//                                      [--library=NAME [--out-dir=DIR]]
 fn main() {
     let context = Context::create();
     let compiler = Compiler::new(&context);
 
-    let func_params = [compiler.i32_type.into(), compiler.i32_type.into()];
-
-    let func_type = compiler.i32_type.fn_type(&func_params, false);
-    let func = compiler.module.add_function("sum", func_type, None);
-    let current_bb = compiler.context.append_basic_block(func, "entry");
-    compiler.builder.position_at_end(current_bb);
-
-    let a_ptr = compiler.builder.build_alloca(compiler.i32_type, "a");
-    compiler
-        .builder
-        .build_store(a_ptr, func.get_nth_param(0).unwrap());
-
-    let b_ptr = compiler.builder.build_alloca(compiler.i32_type, "b");
-    compiler
-        .builder
-        .build_store(b_ptr, func.get_nth_param(1).unwrap());
-
-    let a = compiler
-        .builder
-        .build_load(compiler.i32_type, a_ptr, "a")
-        .into_int_value();
-    let b = compiler
-        .builder
-        .build_load(compiler.i32_type, b_ptr, "b")
-        .into_int_value();
-
-    let c = compiler.builder.build_int_add(a, b, "c");
-    compiler.builder.build_return(Some(&c));
-
-    let main_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "entry");
-    compiler.builder.position_at_end(main_block);
-
-    let a_ptr = compiler.builder.build_alloca(compiler.i32_type, "a");
-    compiler
-        .builder
-        .build_store(a_ptr, compiler.i32_type.const_int(10, false));
-
-    let b_ptr = compiler.builder.build_alloca(compiler.i32_type, "b");
-    compiler
-        .builder
-        .build_store(b_ptr, compiler.i32_type.const_int(20, false));
-
-    let func_args = [
-        compiler
-            .builder
-            .build_load(compiler.i32_type, a_ptr, "a")
-            .into(),
-        compiler
-            .builder
-            .build_load(compiler.i32_type, b_ptr, "b")
-            .into(),
-    ];
-    let sum_func = compiler.module.get_function("sum").unwrap();
-    let c = compiler
-        .builder
-        .build_call(sum_func, &func_args, "sum")
-        .try_as_basic_value()
-        .left()
-        .unwrap()
-        .into_int_value();
-
-    let int_fmt_str = unsafe { compiler.builder.build_global_string("%d\n", "int_fmt_str") };
-
-    compiler.builder.build_call(
-        compiler.printf_func,
-        &[int_fmt_str.as_pointer_value().into(), c.into()],
-        "printf",
-    );
-    let ret_val = compiler.i32_type.const_int(0, false);
-    compiler.builder.build_return(Some(&ret_val));
+    let functions = match std::env::args().nth(1).as_deref() {
+        Some("missing_return") => missing_return_program(),
+        Some("goto") => goto_program(),
+        Some("undefined_label") => undefined_label_program(),
+        Some("exceptions") => exceptions_program(),
+        Some("recursion") => recursion_program(),
+        Some("undefined_function") => undefined_function_program(),
+        Some("variadic") => variadic_program(),
+        Some("closures") => closures_program(),
+        Some("function_pointers") => function_pointers_program(),
+        Some("wrong_signature") => wrong_signature_program(),
+        Some("variadic_ref") => variadic_ref_program(),
+        Some("tail_calls") => tail_calls_program(),
+        Some("annotations") => annotations_program(),
+        Some("impure") => impure_program(),
+        Some("tuples") => tuples_program(),
+        Some("externs") => externs_program(),
+        Some("kernels") => kernels_program(),
+        Some("callbacks") => callbacks_program(),
+        Some("wrong_callback") => wrong_callback_program(),
+        Some("overloads") => overloads_program(),
+        Some("ambiguous") => ambiguous_program(),
+        _ => program(),
+    };
+    let functions = if std::env::args().any(|arg| arg == "--eliminate-tail-recursion") {
+        eliminate_tail_recursion(functions)
+    } else {
+        functions
+    };
+
+    if let Err(e) = compiler.compile_program(&functions) {
+        println!("Error: {e}");
+        return;
+    }
+
+    compiler.module.verify().unwrap();
     compiler.module.print_to_file(Path::new("main.ll")).unwrap();
+
+    if std::env::args().any(|arg| arg == "--jit") {
+        match compiler.run_jit(&host_functions()) {
+            Ok(code) => println!("main returned {code}"),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+
+    let library = std::env::args().find_map(|arg| arg.strip_prefix("--library=").map(String::from));
+    if let Some(library) = library {
+        let out_dir = std::env::args()
+            .find_map(|arg| arg.strip_prefix("--out-dir=").map(String::from))
+            .unwrap_or_else(|| ".".to_string());
+        if let Err(e) = compiler.write_library(&functions, &library, Path::new(&out_dir)) {
+            println!("Error: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use inkwell::targets::{InitializationConfig, Target};
+    use inkwell::OptimizationLevel;
+    use std::cell::Cell;
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+
+    thread_local! {
+        static OUTPUT: RefCell<String> = const { RefCell::new(String::new()) };
+        static LOGGED: RefCell<Vec<i32>> = const { RefCell::new(Vec::new()) };
+        static LAST_ID: Cell<i32> = const { Cell::new(0) };
+    }
+
+    //
+    // Stands in for printf in JIT compiled code. A variadic call on x86-64
+    // passes its integer and pointer arguments in the registers of the i64
+    // parameters and its first double in the one of the f64 parameter, so the
+    // format tells which of them to read.
+    //
+    extern "C" fn record_printf(
+        format: *const c_char,
+        a: i64,
+        b: i64,
+        c: i64,
+        d: i64,
+        e: i64,
+        x: f64,
+    ) -> i32 {
+        let mut ints = [a, b, c, d, e].into_iter();
+        let mut doubles = [x].into_iter();
+        let mut output = String::new();
+        let mut rest = unsafe { CStr::from_ptr(format) }.to_str().unwrap();
+        while let Some(start) = rest.find('%') {
+            output += &rest[..start];
+            rest = &rest[start + 1..];
+            let end = rest.find(|c: char| c.is_ascii_alphabetic()).unwrap() + 1;
+            let (spec, after) = rest.split_at(end);
+            rest = after;
+            match spec.chars().last() {
+                Some('d') => output += &(ints.next().unwrap() as i32).to_string(),
+                Some('s') => {
+                    let s = unsafe { CStr::from_ptr(ints.next().unwrap() as *const c_char) };
+                    output += s.to_str().unwrap();
+                }
+                Some('f') => {
+                    let precision = spec
+                        .strip_prefix('.')
+                        .and_then(|digits| digits.trim_end_matches('f').parse().ok())
+                        .unwrap_or(6);
+                    output += &format!("{:.*}", precision, doubles.next().unwrap());
+                }
+                _ => panic!("unsupported format %{spec}"),
+            }
+        }
+        output += rest;
+        let len = output.len() as i32;
+        OUTPUT.with(|printed| printed.borrow_mut().push_str(&output));
+        len
+    }
+
+    extern "C" fn record_puts(s: *const c_char) -> i32 {
+        let s = unsafe { CStr::from_ptr(s) }.to_str().unwrap();
+        OUTPUT.with(|printed| *printed.borrow_mut() += &format!("{s}\n"));
+        0
+    }
+
+    extern "C" fn record_log(value: i32) {
+        LOGGED.with(|logged| logged.borrow_mut().push(value));
+    }
+
+    extern "C" fn count_ids() -> i32 {
+        LAST_ID.with(|id| {
+            id.set(id.get() + 1);
+            id.get()
+        })
+    }
+
+    fn compile<'ctx>(
+        context: &'ctx Context,
+        functions: &[Function],
+    ) -> Result<Compiler<'ctx>, String> {
+        let compiler = Compiler::new(context);
+        compiler.compile_program(functions)?;
+        compiler.module.verify().unwrap();
+        Ok(compiler)
+    }
+
+    fn compile_error(functions: Vec<Function>) -> String {
+        let context = Context::create();
+        match compile(&context, &functions) {
+            Ok(_) => panic!("program compiled"),
+            Err(e) => e,
+        }
+    }
+
+    // Runs main at -O0 and returns its exit code with what it printed
+    fn run(functions: Vec<Function>) -> (i32, String) {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let context = Context::create();
+        let compiler = compile(&context, &functions).unwrap();
+        let engine = compiler
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        engine.add_global_mapping(&compiler.printf_func, record_printf as *const () as usize);
+        if let Some(puts) = compiler.module.get_function("puts") {
+            engine.add_global_mapping(&puts, record_puts as *const () as usize);
+        }
+        OUTPUT.with(|printed| printed.borrow_mut().clear());
+        let code = unsafe {
+            let main = engine
+                .get_function::<unsafe extern "C" fn() -> i32>(MAIN_FUNC_NAME)
+                .unwrap();
+            main.call()
+        };
+        (code, OUTPUT.with(|printed| printed.take()))
+    }
+
+    fn output(lines: &[&str]) -> String {
+        lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+
+    #[test]
+    fn functions_return_and_fall_off_the_end() {
+        assert_eq!(run(program()), (0, output(&["30", "-1", "35"])));
+    }
+
+    #[test]
+    fn missing_return_is_an_error() {
+        assert_eq!(
+            compile_error(missing_return_program()),
+            "missing return in function positive"
+        );
+    }
+
+    #[test]
+    fn goto_jumps_backward_and_forward() {
+        assert_eq!(run(goto_program()), (5, String::new()));
+    }
+
+    #[test]
+    fn undefined_label_is_an_error() {
+        assert_eq!(
+            compile_error(undefined_label_program()),
+            "undefined label end in function main"
+        );
+    }
+
+    #[test]
+    fn duplicate_label_is_an_error() {
+        let functions = vec![function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![label("again"), label("again"), ret(num(0))],
+        )];
+        assert_eq!(
+            compile_error(functions),
+            "duplicate label again in function main"
+        );
+    }
+
+    #[test]
+    fn exceptions_are_caught_by_class() {
+        let expected = output(&[
+            "5",
+            "100",
+            "caught negative -3",
+            "0",
+            "caught zero 7",
+            "uncaught exception",
+        ]);
+        assert_eq!(run(exceptions_program()), (1, expected));
+    }
+
+    //
+    // int main() {
+    //     printf("before\n");
+    //     throw Zero(4);
+    // }
+    //
+    #[test]
+    fn uncaught_exceptions_exit_with_1() {
+        let functions = vec![function(
+            MAIN_FUNC_NAME,
+            vec![],
+            Type::Int,
+            vec![print("before\n", vec![]), throw("Zero", num(4))],
+        )];
+        assert_eq!(
+            run(functions),
+            (1, output(&["before", "uncaught exception"]))
+        );
+    }
+
+    //
+    // int classify(int a) {
+    //     try {
+    //         try {
+    //             if (a < 0) {
+    //                 throw Negative(a);
+    //             }
+    //             if (a == 0) {
+    //                 throw Zero(a);
+    //             }
+    //         } catch (Negative n) {
+    //             return 1;
+    //         }
+    //     } catch (Zero z) {
+    //         return 2;
+    //     }
+    //     return 3;
+    // }
+    //
+    // int main() {
+    //     return classify(0 - 1) * 100 + classify(0) * 10 + classify(5);
+    // }
+    //
+    #[test]
+    fn nested_try_passes_on_what_it_does_not_catch() {
+        let inner = Stmt::Try {
+            body: vec![
+                Stmt::If {
+                    cond: binary(BinaryOp::Lt, var("a"), num(0)),
+                    then_body: vec![throw("Negative", var("a"))],
+                    else_body: vec![],
+                },
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("a"), num(0)),
+                    then_body: vec![throw("Zero", var("a"))],
+                    else_body: vec![],
+                },
+            ],
+            catches: vec![catch("Negative", "n", vec![ret(num(1))])],
+        };
+        let classify = |a| call("classify", vec![a]);
+        let functions = vec![
+            function(
+                "classify",
+                vec![param("a", Type::Int)],
+                Type::Int,
+                vec![
+                    Stmt::Try {
+                        body: vec![inner],
+                        catches: vec![catch("Zero", "z", vec![ret(num(2))])],
+                    },
+                    ret(num(3)),
+                ],
+            ),
+            function(
+                MAIN_FUNC_NAME,
+                vec![],
+                Type::Int,
+                vec![ret(binary(
+                    BinaryOp::Add,
+                    binary(
+                        BinaryOp::Add,
+                        binary(
+                            BinaryOp::Mul,
+                            classify(binary(BinaryOp::Sub, num(0), num(1))),
+                            num(100),
+                        ),
+                        binary(BinaryOp::Mul, classify(num(0)), num(10)),
+                    ),
+                    classify(num(5)),
+                ))],
+            ),
+        ];
+        assert_eq!(run(functions), (123, String::new()));
+    }
+
+    //
+    // int convert() {
+    //     try {
+    //         throw Negative(1);
+    //     } catch (Negative n) {
+    //         throw Zero(n + 1);  /* ends the catch of Negative first */
+    //     }
+    //     return 0;
+    // }
+    //
+    // int main() {
+    //     int total = 0;
+    //     int i = 0;
+    //     while (i < 3) {
+    //         try {
+    //             convert();
+    //         } catch (Zero z) {
+    //             total = total + z;
+    //         }
+    //         i = i + 1;
+    //     }
+    //     return total;
+    // }
+    //
+    #[test]
+    fn throwing_from_a_catch_ends_it() {
+        let functions = vec![
+            function(
+                "convert",
+                vec![],
+                Type::Int,
+                vec![
+                    Stmt::Try {
+                        body: vec![throw("Negative", num(1))],
+                        catches: vec![catch(
+                            "Negative",
+                            "n",
+                            vec![throw("Zero", binary(BinaryOp::Add, var("n"), num(1)))],
+                        )],
+                    },
+                    ret(num(0)),
+                ],
+            ),
+            function(
+                MAIN_FUNC_NAME,
+                vec![],
+                Type::Int,
+                vec![
+                    declare("total", num(0)),
+                    declare("i", num(0)),
+                    Stmt::While {
+                        cond: binary(BinaryOp::Lt, var("i"), num(3)),
+                        body: vec![
+                            Stmt::Try {
+                                body: vec![Stmt::Expr(call("convert", vec![]))],
+                                catches: vec![catch(
+                                    "Zero",
+                                    "z",
+                                    vec![assign(
+                                        "total",
+                                        binary(BinaryOp::Add, var("total"), var("z")),
+                                    )],
+                                )],
+                            },
+                            assign("i", binary(BinaryOp::Add, var("i"), num(1))),
+                        ],
+                    },
+                    ret(var("total")),
+                ],
+            ),
+        ];
+
+        let context = Context::create();
+        let compiler = compile(&context, &functions).unwrap();
+        let ir = compiler.module.print_to_string().to_string();
+        let convert: Vec<&str> = ir
+            .lines()
+            .skip_while(|line| !line.starts_with("define i32 @convert("))
+            .take_while(|line| *line != "}")
+            .collect();
+        let position = |call: &str| {
+            convert
+                .iter()
+                .position(|line| line.contains(call))
+                .unwrap_or_else(|| panic!("no {call} in\n{ir}"))
+        };
+        let begin_catch = position("@__cxa_begin_catch(");
+        let end_catch = position("call void @__cxa_end_catch()");
+        let rethrow = position("call void @__cxa_throw(");
+        assert!(begin_catch < end_catch && end_catch < rethrow);
+
+        assert_eq!(run(functions), (6, String::new()));
+    }
+
+    #[test]
+    fn functions_recurse_and_call_functions_defined_later() {
+        assert_eq!(run(recursion_program()), (1, output(&["120", "55", "1"])));
+    }
+
+    #[test]
+    fn undefined_function_is_an_error() {
+        assert_eq!(
+            compile_error(undefined_function_program()),
+            "undefined function missing"
+        );
+    }
+
+    #[test]
+    fn variadic_functions_read_their_arguments() {
+        assert_eq!(run(variadic_program()), (9, output(&["60", "0"])));
+    }
+
+    #[test]
+    fn closures_capture_by_value() {
+        assert_eq!(
+            run(closures_program()),
+            (17, output(&["11", "11", "35", "1.5"]))
+        );
+    }
+
+    #[test]
+    fn function_pointers_are_passed_and_returned() {
+        assert_eq!(
+            run(function_pointers_program()),
+            (7, output(&["3", "7", "3"]))
+        );
+    }
+
+    #[test]
+    fn wrong_function_pointer_signature_is_an_error() {
+        assert_eq!(
+            compile_error(wrong_signature_program()),
+            "argument 1 of apply has the wrong type: FunctionRef(\"negate\")"
+        );
+    }
+
+    #[test]
+    fn variadic_function_reference_is_an_error() {
+        assert_eq!(
+            compile_error(variadic_ref_program()),
+            "variadic function sum_all cannot be referenced"
+        );
+    }
+
+    // A million frames of count_down would overflow the stack, every call in
+    // tail position has to be a jump even without optimizations
+    #[test]
+    fn static_functions_recurse_in_constant_stack_at_o0() {
+        assert_eq!(run(tail_calls_program()), (0, output(&["1000000", "0"])));
+        let context = Context::create();
+        let compiler = compile(&context, &tail_calls_program()).unwrap();
+        let ir = compiler.module.print_to_string().to_string();
+        assert!(ir.contains("tail call tailcc i32 @count_down("));
+        assert!(ir.contains("tail call tailcc i32 @is_odd("));
+    }
+
+    #[test]
+    fn annotated_functions_run() {
+        assert_eq!(run(annotations_program()), (0, output(&["49", "27", "55"])));
+    }
+
+    #[test]
+    fn impure_pure_function_is_an_error() {
+        assert_eq!(
+            compile_error(impure_program()),
+            "pure function log_square prints"
+        );
+    }
+
+    #[test]
+    fn tuples_are_returned_and_destructured() {
+        assert_eq!(run(tuples_program()), (5, output(&["3 2", "2 4 6 8 10"])));
+    }
+
+    #[test]
+    fn extern_functions_come_from_the_c_library() {
+        assert_eq!(
+            run(externs_program()),
+            (3, output(&["hello from puts", "2.828427 via printf"]))
+        );
+    }
+
+    #[test]
+    fn libraries_come_with_a_c_header_and_rust_bindings() {
+        let context = Context::create();
+        let functions = kernels_program();
+        let compiler = compile(&context, &functions).unwrap();
+        let out_dir =
+            std::env::temp_dir().join(format!("llvm-function-kernels-{}", std::process::id()));
+        compiler
+            .write_library(&functions, "kernels", &out_dir)
+            .unwrap();
+        let read = |file: &str| std::fs::read_to_string(out_dir.join(file)).unwrap();
+        assert_eq!(
+            read("kernels.h"),
+            "// Generated from main.ll\n\
+             #ifndef KERNELS_H\n\
+             #define KERNELS_H\n\
+             \n\
+             struct tuple2 { int _0; int _1; };\n\
+             int sum_of_squares(int a, int b);\n\
+             double scale(double x, double factor);\n\
+             struct tuple2 minmax(int a, int b);\n\
+             int apply(int (*f)(int, int), int x);\n\
+             \n\
+             #endif // KERNELS_H\n"
+        );
+        let bindings = read("kernels.rs");
+        assert!(bindings.contains("    pub fn minmax(a: i32, b: i32) -> Tuple2;\n"));
+        assert!(bindings
+            .contains("    pub fn apply(f: extern \"C\" fn(i32, i32) -> i32, x: i32) -> i32;\n"));
+        assert!(out_dir.join("libkernels.so").exists());
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+
+    #[test]
+    fn jit_code_calls_back_into_rust() {
+        let context = Context::create();
+        let compiler = compile(&context, &callbacks_program()).unwrap();
+        LOGGED.with(|logged| logged.borrow_mut().clear());
+        LAST_ID.with(|id| id.set(0));
+        let host_functions = [
+            HostFunction::new("log_value", record_log as extern "C" fn(i32)),
+            HostFunction::new("next_id", count_ids as extern "C" fn() -> i32),
+        ];
+        assert_eq!(compiler.run_jit(&host_functions), Ok(3));
+        assert_eq!(LOGGED.with(|logged| logged.take()), vec![21]);
+    }
+
+    #[test]
+    fn wrong_callback_signature_is_an_error() {
+        let context = Context::create();
+        let compiler = compile(&context, &wrong_callback_program()).unwrap();
+        assert_eq!(
+            compiler.run_jit(&host_functions()),
+            Err("host function log_value does not match its declaration: \
+                 expected Function { params: [Double], return_type: Void }, \
+                 got Function { params: [Int], return_type: Void }"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn overloads_are_picked_by_argument_types() {
+        assert_eq!(
+            run(overloads_program()),
+            (0, output(&["int 7", "double 2.500000", "double 6.000000"]))
+        );
+    }
+
+    #[test]
+    fn ambiguous_overload_is_an_error() {
+        assert_eq!(
+            compile_error(ambiguous_program()),
+            "ambiguous call to pick with [Number, Number]"
+        );
+    }
 }
@@ -0,0 +1,136 @@
+use crate::ast::*;
+use std::collections::HashSet;
+
+const LABEL: &str = "tail_recursion";
+
+//
+// Turns self tail calls into a jump back to the start of the function:
+//
+// int count_down(int n) {             int count_down(int n) {
+//     if (n == 0) {                   tail_recursion:
+//         return 0;                       if (n == 0) {
+//     }                                       return 0;
+//     return count_down(n - 1);  =>       }
+// }                                       {
+//                                             int tail.n = n - 1;
+//                                             n = tail.n;
+//                                             goto tail_recursion;
+//                                         }
+//                                     }
+//
+// The arguments go through temporaries since they may read parameters that
+// are assigned before them. Calls inside a try are not in tail position and
+// are left alone, as are overloaded functions, whose calls may pick another
+// overload. So are functions taking a double: a temporary gets the type of
+// its argument, and an int passed to a double parameter is only converted by
+// a call.
+//
+pub fn eliminate_tail_recursion(functions: Vec<Function>) -> Vec<Function> {
+    let mut names = HashSet::new();
+    let overloaded: HashSet<String> = functions
+        .iter()
+        .filter(|function| !names.insert(function.name.as_str()))
+        .map(|function| function.name.clone())
+        .collect();
+    functions
+        .into_iter()
+        .map(|function| {
+            if overloaded.contains(&function.name) {
+                function
+            } else {
+                eliminate_in(function)
+            }
+        })
+        .collect()
+}
+
+fn eliminate_in(function: Function) -> Function {
+    let takes_double = function
+        .params
+        .iter()
+        .any(|param| param.param_type == Type::Double);
+    if function.variadic || takes_double || !is_candidate(&function) {
+        return function;
+    }
+
+    let mut found = false;
+    let body: Vec<Stmt> = function
+        .body
+        .iter()
+        .map(|stmt| rewrite(&function, stmt, &mut found))
+        .collect();
+    if !found {
+        return function;
+    }
+
+    Function {
+        body: std::iter::once(label(LABEL)).chain(body).collect(),
+        ..function
+    }
+}
+
+// A local declared with the name of the function or of one of its
+// parameters would shadow what the rewrite refers to
+fn is_candidate(function: &Function) -> bool {
+    let shadows =
+        |name: &String| *name == function.name || function.params.iter().any(|p| p.name == *name);
+    let mut candidate = true;
+    for stmt in &function.body {
+        stmt.walk(&mut |node| match node {
+            Node::Stmt(Stmt::Declare(name, _)) if shadows(name) => candidate = false,
+            Node::Stmt(Stmt::Destructure(names, _)) if names.iter().any(shadows) => {
+                candidate = false
+            }
+            Node::Stmt(Stmt::Label(name)) if name == LABEL => candidate = false,
+            _ => {}
+        });
+    }
+    candidate
+}
+
+fn rewrite(function: &Function, stmt: &Stmt, found: &mut bool) -> Stmt {
+    let rewrite_all = |stmts: &[Stmt], found: &mut bool| -> Vec<Stmt> {
+        stmts
+            .iter()
+            .map(|stmt| rewrite(function, stmt, found))
+            .collect()
+    };
+
+    match stmt {
+        Stmt::Return(Some(Expr::Call(name, args)))
+            if *name == function.name && args.len() == function.params.len() =>
+        {
+            *found = true;
+            let temporaries = function
+                .params
+                .iter()
+                .zip(args)
+                .map(|(param, arg)| declare(&format!("tail.{}", param.name), arg.clone()));
+            let assignments = function
+                .params
+                .iter()
+                .map(|param| assign(&param.name, var(&format!("tail.{}", param.name))));
+            Stmt::Block(
+                temporaries
+                    .chain(assignments)
+                    .chain(std::iter::once(goto(LABEL)))
+                    .collect(),
+            )
+        }
+        Stmt::Block(stmts) => Stmt::Block(rewrite_all(stmts, found)),
+        Stmt::If {
+            cond,
+            then_body,
+            else_body,
+        } => Stmt::If {
+            cond: cond.clone(),
+            then_body: rewrite_all(then_body, found),
+            else_body: rewrite_all(else_body, found),
+        },
+        Stmt::While { cond, body } => Stmt::While {
+            cond: cond.clone(),
+            body: rewrite_all(body, found),
+        },
+        _ => stmt.clone(),
+    }
+}
@@ -0,0 +1,249 @@
+use crate::ast::*;
+use crate::env::*;
+use crate::Compiler;
+
+use inkwell::attributes::{Attribute, AttributeLoc};
+use inkwell::types::{BasicTypeEnum, StructType};
+use inkwell::values::{FunctionValue, IntValue, PointerValue};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// How a tuple leaves the function that returns it
+pub enum TupleAbi<'ctx> {
+    // In registers, as a value of this type
+    Direct(BasicTypeEnum<'ctx>),
+    // Through a hidden sret pointer to memory of the caller
+    Indirect,
+}
+
+//
+// Tuples of ints are returned like the x86-64 System V ABI returns a struct
+// of ints:
+//
+//   (int, int)                i64           in rax
+//   (int, int, int)           { i64, i32 }  in rax and rdx
+//   (int, int, int, int)      { i64, i64 }  in rax and rdx
+//   larger                    in memory
+//
+// Other targets classify structs differently, check_tuple refuses them.
+//
+// so that
+//
+//   (int, int) divmod(int a, int b);
+//
+// returns its values where the C function
+//
+//   struct { int quotient; int remainder; } divmod(int a, int b);
+//
+// would.
+//
+// Register returns go through a local struct, the callee stores the values
+// into it and loads it back as the register type, the caller does the
+// opposite. Larger tuples are written through a pointer the caller passes as
+// hidden first parameter:
+//
+//   void minmax3(struct { int, int, int, int, int } *sret, int a, int b, int c);
+//
+impl<'ctx> Compiler<'ctx> {
+    pub fn tuple_type(&self, len: usize) -> StructType<'ctx> {
+        let field_types: Vec<BasicTypeEnum> = (0..len).map(|_| self.i32_type.into()).collect();
+        self.context.struct_type(&field_types, false)
+    }
+
+    pub fn tuple_abi(&self, len: usize) -> TupleAbi<'ctx> {
+        let i64_type = self.context.i64_type();
+        match len {
+            0..=2 => TupleAbi::Direct(i64_type.into()),
+            3 => TupleAbi::Direct(
+                self.context
+                    .struct_type(&[i64_type.into(), self.i32_type.into()], false)
+                    .into(),
+            ),
+            4 => TupleAbi::Direct(
+                self.context
+                    .struct_type(&[i64_type.into(), i64_type.into()], false)
+                    .into(),
+            ),
+            _ => TupleAbi::Indirect,
+        }
+    }
+
+    pub fn returns_in_memory(&self, return_type: &Type) -> bool {
+        match return_type {
+            Type::Tuple(elements) => matches!(self.tuple_abi(elements.len()), TupleAbi::Indirect),
+            _ => false,
+        }
+    }
+
+    pub fn check_tuple(&self, function: &Function) -> Result<(), String> {
+        if let Type::Tuple(elements) = &function.return_type {
+            // tuple_abi hardcodes how x86-64 returns small structs
+            self.check_sysv_target("tuple returns")?;
+            if elements.len() < 2 || elements.iter().any(|element| *element != Type::Int) {
+                return Err(format!(
+                    "function {} must return a tuple of at least two ints",
+                    function.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_sret_attribute(&self, func: FunctionValue<'ctx>, function: &Function) {
+        if let Type::Tuple(elements) = &function.return_type {
+            if self.returns_in_memory(&function.return_type) {
+                let attribute = self.context.create_type_attribute(
+                    Attribute::get_named_enum_kind_id("sret"),
+                    self.tuple_type(elements.len()).into(),
+                );
+                func.add_attribute(AttributeLoc::Param(0), attribute);
+            }
+        }
+    }
+
+    // The sret pointer of a function returning its tuple in memory
+    pub fn sret_param(
+        &self,
+        func: FunctionValue<'ctx>,
+        function: &Function,
+    ) -> Option<PointerValue<'ctx>> {
+        if self.returns_in_memory(&function.return_type) {
+            Some(func.get_first_param().unwrap().into_pointer_value())
+        } else {
+            None
+        }
+    }
+
+    fn store_tuple(&self, ptr: PointerValue<'ctx>, values: &[IntValue<'ctx>]) {
+        let tuple_type = self.tuple_type(values.len());
+        for (i, value) in values.iter().enumerate() {
+            let field = self
+                .builder
+                .build_struct_gep(tuple_type, ptr, i as u32, "field")
+                .unwrap();
+            self.builder.build_store(field, *value);
+        }
+    }
+
+    fn load_tuple(&self, ptr: PointerValue<'ctx>, names: &[String]) -> Vec<IntValue<'ctx>> {
+        let tuple_type = self.tuple_type(names.len());
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let field = self
+                    .builder
+                    .build_struct_gep(tuple_type, ptr, i as u32, name)
+                    .unwrap();
+                self.builder
+                    .build_load(self.i32_type, field, name)
+                    .into_int_value()
+            })
+            .collect()
+    }
+
+    pub fn compile_tuple_return(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        len: usize,
+        value: Option<&Expr>,
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let exprs = match value {
+            Some(Expr::Tuple(exprs)) if exprs.len() == len => exprs,
+            _ => {
+                return Err(format!(
+                    "function {} must return a tuple of {} values",
+                    func.get_name().to_string_lossy(),
+                    len
+                ))
+            }
+        };
+        let mut values = Vec::new();
+        for expr in exprs {
+            values.push(self.compile_int(env, expr)?);
+        }
+
+        match self.tuple_abi(len) {
+            TupleAbi::Direct(return_type) => {
+                let ptr = self.build_entry_alloca(return_type, "tuple");
+                self.store_tuple(ptr, &values);
+                let value = self.builder.build_load(return_type, ptr, "tuple");
+                self.end_active_catches();
+                self.end_varargs();
+                self.builder.build_return(Some(&value));
+            }
+            TupleAbi::Indirect => {
+                self.store_tuple(self.sret.get().unwrap(), &values);
+                self.end_active_catches();
+                self.end_varargs();
+                self.builder.build_return(None);
+            }
+        }
+        Ok(())
+    }
+
+    // auto [names] = callee(args), only direct calls return tuples
+    pub fn compile_destructure(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        names: &[String],
+        value: &Expr,
+    ) -> Result<(), String> {
+        let (callee, args) = match value {
+            Expr::Call(callee, args) if env.borrow().get(callee).is_none() => (callee, args),
+            _ => {
+                return Err(format!(
+                    "only calls to functions returning tuples can be destructured: {value:?}"
+                ))
+            }
+        };
+        let entry = self.resolve_call(env, callee, args)?;
+        let len = match entry.return_type() {
+            Type::Tuple(elements) => elements.len(),
+            _ => return Err(format!("function {callee} does not return a tuple")),
+        };
+        if names.len() != len {
+            return Err(format!(
+                "cannot destructure {} values from {}, which returns {}",
+                names.len(),
+                callee,
+                len
+            ));
+        }
+
+        let func = entry.func;
+        let mut call_args = self.compile_args(env, entry.params(), args)?;
+        let ptr = match self.tuple_abi(len) {
+            TupleAbi::Direct(return_type) => {
+                let value = self
+                    .build_call_or_invoke(func, &call_args, callee)
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap();
+                let ptr = self.build_entry_alloca(return_type, callee);
+                self.builder.build_store(ptr, value);
+                ptr
+            }
+            TupleAbi::Indirect => {
+                let ptr = self.build_entry_alloca(self.tuple_type(len), callee);
+                call_args.insert(0, ptr.into());
+                self.build_call_or_invoke(func, &call_args, "");
+                ptr
+            }
+        };
+
+        for (name, value) in names.iter().zip(self.load_tuple(ptr, names)) {
+            let ptr = self.build_entry_alloca(self.i32_type, name);
+            self.builder.build_store(ptr, value);
+            env.borrow_mut().add(
+                name,
+                Pointer {
+                    ptr,
+                    data_type: DataType::Number,
+                },
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Gt,
+    Eq,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i64),
+    Variable(String),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    // cond ? then_value : else_value
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    // if (cond) { ...; value } else { ...; value }
+    If {
+        cond: Box<Expr>,
+        then_block: BlockExpr,
+        else_block: BlockExpr,
+    },
+}
+
+// A block whose value is its trailing expression
+#[derive(Debug, Clone)]
+pub struct BlockExpr {
+    pub stmts: Vec<Stmt>,
+    pub value: Box<Expr>,
+}
+
+// One `if (cond)` or `else if (cond)` arm
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub cond: Expr,
+    pub body: Vec<Stmt>,
+}
+
+// A `case value:` label, or `default:` when value is None
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub value: Option<i64>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Declare(String, Expr),
+    Assign(String, Expr),
+    Print(String, Vec<Expr>),
+    If {
+        branches: Vec<Branch>,
+        else_body: Option<Vec<Stmt>>,
+    },
+    Switch {
+        value: Expr,
+        cases: Vec<Case>,
+    },
+    Break,
+    Return(Expr),
+}
+
+pub fn num(value: i64) -> Expr {
+    Expr::Number(value)
+}
+
+pub fn var(name: &str) -> Expr {
+    Expr::Variable(name.to_string())
+}
+
+pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+}
+
+pub fn ternary(cond: Expr, then_value: Expr, else_value: Expr) -> Expr {
+    Expr::Ternary(Box::new(cond), Box::new(then_value), Box::new(else_value))
+}
+
+pub fn block_expr(stmts: Vec<Stmt>, value: Expr) -> BlockExpr {
+    BlockExpr {
+        stmts,
+        value: Box::new(value),
+    }
+}
+
+pub fn declare(name: &str, value: Expr) -> Stmt {
+    Stmt::Declare(name.to_string(), value)
+}
+
+pub fn assign(name: &str, value: Expr) -> Stmt {
+    Stmt::Assign(name.to_string(), value)
+}
+
+pub fn print(fmt: &str, args: Vec<Expr>) -> Stmt {
+    Stmt::Print(fmt.to_string(), args)
+}
+
+pub fn branch(cond: Expr, body: Vec<Stmt>) -> Branch {
+    Branch { cond, body }
+}
+
+pub fn case(value: i64, body: Vec<Stmt>) -> Case {
+    Case {
+        value: Some(value),
+        body,
+    }
+}
+
+pub fn default(body: Vec<Stmt>) -> Case {
+    Case { value: None, body }
+}
@@ -1,11 +1,20 @@
+mod ast;
+mod env;
+use crate::ast::*;
+use crate::env::*;
+use std::collections::HashSet;
 use std::path::Path;
 
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::module::Module;
 use inkwell::types::IntType;
-use inkwell::values::FunctionValue;
+use inkwell::values::{BasicMetadataValueEnum, FunctionValue, IntValue, PointerValue};
 use inkwell::AddressSpace;
+use inkwell::IntPredicate;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 const MAIN_FUNC_NAME: &str = "main";
 
@@ -17,6 +26,9 @@ pub struct Compiler<'ctx> {
 
     pub main_func: FunctionValue<'ctx>,
     pub printf_func: FunctionValue<'ctx>,
+
+    // Blocks that a break statement exits to, innermost last
+    pub breaks: RefCell<Vec<BasicBlock<'ctx>>>,
 }
 
 impl<'ctx> Compiler<'ctx> {
@@ -38,104 +50,845 @@ impl<'ctx> Compiler<'ctx> {
             i32_type,
             main_func,
             printf_func,
+            breaks: RefCell::new(Vec::new()),
         }
     }
 }
 
+fn int_predicate(op: BinaryOp) -> Option<IntPredicate> {
+    match op {
+        BinaryOp::Gt => Some(IntPredicate::SGT),
+        BinaryOp::Eq => Some(IntPredicate::EQ),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => None,
+    }
+}
+
+impl<'ctx> Compiler<'ctx> {
+    fn current_function(&self) -> FunctionValue<'ctx> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .unwrap()
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some()
+    }
+
+    // Fall through to the given block unless a break already left the current one
+    fn branch_to(&self, block: BasicBlock<'ctx>) {
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(block);
+        }
+    }
+
+    //
+    // Variables live in the entry block, whichever block declares them. A case
+    // of a switch can use a variable declared by an earlier case it falls
+    // through from, and the jump to that case must not skip the alloca.
+    //
+    fn build_entry_alloca(&self, ty: IntType<'ctx>, name: &str) -> PointerValue<'ctx> {
+        let entry_block = self.current_function().get_first_basic_block().unwrap();
+        let builder = self.context.create_builder();
+        match entry_block.get_first_instruction() {
+            Some(instruction) => builder.position_before(&instruction),
+            None => builder.position_at_end(entry_block),
+        }
+        builder.build_alloca(ty, name)
+    }
+
+    fn llvm_type(&self, data_type: &DataType) -> IntType<'ctx> {
+        match data_type {
+            DataType::Number => self.i32_type,
+        }
+    }
+
+    fn compile_expr(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        expr: &Expr,
+    ) -> Result<IntValue<'ctx>, String> {
+        match expr {
+            Expr::Number(value) => Ok(self.i32_type.const_int(*value as u64, true)),
+            Expr::Variable(name) => {
+                let pointer = env
+                    .borrow()
+                    .get(name)
+                    .ok_or_else(|| format!("undefined variable {name}"))?;
+                Ok(self
+                    .builder
+                    .build_load(self.llvm_type(&pointer.data_type), pointer.ptr, name)
+                    .into_int_value())
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.compile_expr(env, lhs)?;
+                let rhs = self.compile_expr(env, rhs)?;
+                Ok(match op {
+                    BinaryOp::Add => self.builder.build_int_add(lhs, rhs, "add"),
+                    BinaryOp::Sub => self.builder.build_int_sub(lhs, rhs, "sub"),
+                    BinaryOp::Mul => self.builder.build_int_mul(lhs, rhs, "mul"),
+                    _ => {
+                        let predicate = int_predicate(*op).unwrap();
+                        let cmp = self.builder.build_int_compare(predicate, lhs, rhs, "cmp");
+                        self.builder.build_int_z_extend(cmp, self.i32_type, "cmp")
+                    }
+                })
+            }
+            Expr::Ternary(cond, then_value, else_value) => self.compile_conditional_value(
+                env,
+                cond,
+                |env| self.compile_expr(env, then_value).map(Some),
+                |env| self.compile_expr(env, else_value).map(Some),
+            ),
+            Expr::If {
+                cond,
+                then_block,
+                else_block,
+            } => self.compile_conditional_value(
+                env,
+                cond,
+                |env| self.compile_block_expr(env, then_block),
+                |env| self.compile_block_expr(env, else_block),
+            ),
+        }
+    }
+
+    // Returns None when a statement in the block leaves it before the value
+    fn compile_block_expr(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        block: &BlockExpr,
+    ) -> Result<Option<IntValue<'ctx>>, String> {
+        let block_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
+        self.compile_stmts(&block_env, &block.stmts)?;
+        if self.is_terminated() {
+            return Ok(None);
+        }
+        self.compile_expr(&block_env, &block.value).map(Some)
+    }
+
+    //
+    // Both arms are generated into their own blocks and their values are
+    // merged by a phi. An arm may contain nested conditionals, so the phi takes
+    // its incoming block from wherever the builder ended up rather than from
+    // the block the arm started in.
+    //
+    fn compile_conditional_value<T, E>(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        cond: &Expr,
+        then_arm: T,
+        else_arm: E,
+    ) -> Result<IntValue<'ctx>, String>
+    where
+        T: FnOnce(&Rc<RefCell<Env<'ctx>>>) -> Result<Option<IntValue<'ctx>>, String>,
+        E: FnOnce(&Rc<RefCell<Env<'ctx>>>) -> Result<Option<IntValue<'ctx>>, String>,
+    {
+        let func = self.current_function();
+        let comparison = self.compile_cond(env, cond)?;
+        let cond_true_block = self.context.append_basic_block(func, "cond_true");
+        let cond_false_block = self.context.append_basic_block(func, "cond_false");
+        let cond_merge_block = self.context.append_basic_block(func, "cond_merge");
+        self.builder
+            .build_conditional_branch(comparison, cond_true_block, cond_false_block);
+
+        let mut incoming = Vec::new();
+
+        // Generate code for cond true block
+        self.builder.position_at_end(cond_true_block);
+        if let Some(value) = then_arm(env)? {
+            incoming.push((value, self.builder.get_insert_block().unwrap()));
+            self.builder.build_unconditional_branch(cond_merge_block);
+        }
+
+        // Generate code for cond false block
+        cond_false_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(cond_false_block);
+        if let Some(value) = else_arm(env)? {
+            incoming.push((value, self.builder.get_insert_block().unwrap()));
+            self.builder.build_unconditional_branch(cond_merge_block);
+        }
+
+        if incoming.is_empty() {
+            return Err("conditional expression never produces a value".to_string());
+        }
+
+        // Generate code for cond merge block
+        cond_merge_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(cond_merge_block);
+        let phi = self.builder.build_phi(self.i32_type, "cond_value");
+        for (value, block) in incoming {
+            phi.add_incoming(&[(&value, block)]);
+        }
+        Ok(phi.as_basic_value().into_int_value())
+    }
+
+    // Comparisons are branched on directly, anything else is true when non-zero
+    fn compile_cond(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        expr: &Expr,
+    ) -> Result<IntValue<'ctx>, String> {
+        if let Expr::Binary(op, lhs, rhs) = expr {
+            if let Some(predicate) = int_predicate(*op) {
+                let lhs = self.compile_expr(env, lhs)?;
+                let rhs = self.compile_expr(env, rhs)?;
+                return Ok(self.builder.build_int_compare(predicate, lhs, rhs, "cond"));
+            }
+        }
+        let value = self.compile_expr(env, expr)?;
+        Ok(self.builder.build_int_compare(
+            IntPredicate::NE,
+            value,
+            self.i32_type.const_zero(),
+            "cond",
+        ))
+    }
+
+    // Statements following a break are unreachable and skipped
+    fn compile_stmts(&self, env: &Rc<RefCell<Env<'ctx>>>, stmts: &[Stmt]) -> Result<(), String> {
+        for stmt in stmts {
+            if self.is_terminated() {
+                break;
+            }
+            self.compile_stmt(env, stmt)?;
+        }
+        Ok(())
+    }
+
+    // Every block gets its own Env so that declarations do not leak out of it
+    fn compile_block(&self, env: &Rc<RefCell<Env<'ctx>>>, stmts: &[Stmt]) -> Result<(), String> {
+        let block_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
+        self.compile_stmts(&block_env, stmts)
+    }
+
+    fn compile_stmt(&self, env: &Rc<RefCell<Env<'ctx>>>, stmt: &Stmt) -> Result<(), String> {
+        match stmt {
+            Stmt::Declare(name, value) => {
+                let value = self.compile_expr(env, value)?;
+                let ptr = self.build_entry_alloca(self.i32_type, name);
+                self.builder.build_store(ptr, value);
+                env.borrow_mut().add(
+                    name,
+                    Pointer {
+                        ptr,
+                        data_type: DataType::Number,
+                    },
+                );
+            }
+            Stmt::Assign(name, value) => {
+                let value = self.compile_expr(env, value)?;
+                let ptr = env
+                    .borrow()
+                    .get(name)
+                    .ok_or_else(|| format!("undefined variable {name}"))?
+                    .ptr;
+                self.builder.build_store(ptr, value);
+            }
+            Stmt::Print(fmt, args) => {
+                let fmt_str = unsafe { self.builder.build_global_string(fmt, "fmt_str") };
+                let mut call_args: Vec<BasicMetadataValueEnum> =
+                    vec![fmt_str.as_pointer_value().into()];
+                for arg in args {
+                    call_args.push(self.compile_expr(env, arg)?.into());
+                }
+                self.builder
+                    .build_call(self.printf_func, &call_args, "printf");
+            }
+            Stmt::If {
+                branches,
+                else_body,
+            } => self.compile_if(env, branches, else_body.as_deref())?,
+            Stmt::Switch { value, cases } => self.compile_switch(env, value, cases)?,
+            Stmt::Break => {
+                let break_block = *self
+                    .breaks
+                    .borrow()
+                    .last()
+                    .ok_or_else(|| "break outside of a switch".to_string())?;
+                self.builder.build_unconditional_branch(break_block);
+            }
+            Stmt::Return(value) => {
+                let value = self.compile_expr(env, value)?;
+                self.builder.build_return(Some(&value));
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_block(
+        &self,
+        func: FunctionValue<'ctx>,
+        merge_block: &mut Option<BasicBlock<'ctx>>,
+    ) -> BasicBlock<'ctx> {
+        *merge_block.get_or_insert_with(|| self.context.append_basic_block(func, "merge"))
+    }
+
+    //
+    // if (c1) { ... } else if (c2) { ... } else { ... }
+    //
+    // Each condition after the first is checked in its own else_if block. The
+    // merge block is only created once an arm falls through to it, so a chain
+    // whose arms all end in break leaves the builder in a terminated block.
+    //
+    fn compile_if(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        branches: &[Branch],
+        else_body: Option<&[Stmt]>,
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let mut merge_block = None;
+
+        for (i, branch) in branches.iter().enumerate() {
+            let comparison = self.compile_cond(env, &branch.cond)?;
+            let if_true_block = self.context.append_basic_block(func, "if_true");
+            let is_last = i + 1 == branches.len();
+            let if_false_block = if !is_last {
+                self.context.append_basic_block(func, "else_if")
+            } else if else_body.is_some() {
+                self.context.append_basic_block(func, "if_false")
+            } else {
+                self.merge_block(func, &mut merge_block)
+            };
+            self.builder
+                .build_conditional_branch(comparison, if_true_block, if_false_block);
+
+            // Generate code for if true block
+            if_true_block
+                .move_after(self.builder.get_insert_block().unwrap())
+                .unwrap();
+            self.builder.position_at_end(if_true_block);
+            self.compile_block(env, &branch.body)?;
+            if !self.is_terminated() {
+                let merge_block = self.merge_block(func, &mut merge_block);
+                self.builder.build_unconditional_branch(merge_block);
+            }
+
+            // The next condition or the else arm is generated in the false block
+            if !is_last || else_body.is_some() {
+                if_false_block
+                    .move_after(self.builder.get_insert_block().unwrap())
+                    .unwrap();
+                self.builder.position_at_end(if_false_block);
+            }
+        }
+
+        // Generate code for if false block
+        if let Some(else_body) = else_body {
+            self.compile_block(env, else_body)?;
+            if !self.is_terminated() {
+                let merge_block = self.merge_block(func, &mut merge_block);
+                self.builder.build_unconditional_branch(merge_block);
+            }
+        }
+
+        // Generate code for merge block
+        if let Some(merge_block) = merge_block {
+            merge_block
+                .move_after(self.builder.get_insert_block().unwrap())
+                .unwrap();
+            self.builder.position_at_end(merge_block);
+        }
+        Ok(())
+    }
+
+    //
+    // switch (value) { case 1: ... case 2: ... default: ... }
+    //
+    // Every label gets its own block and the LLVM switch instruction jumps to
+    // it directly. A case that does not break falls through to the next label
+    // in source order, like C.
+    //
+    fn compile_switch(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        value: &Expr,
+        cases: &[Case],
+    ) -> Result<(), String> {
+        let value = self.compile_expr(env, value)?;
+        let func = self.current_function();
+
+        let mut seen = HashSet::new();
+        let mut case_blocks = Vec::new();
+        let mut switch_cases = Vec::new();
+        let mut default_block = None;
+        for case in cases {
+            match case.value {
+                Some(case_value) => {
+                    // The switch compares i32 values, a wider label would be truncated
+                    if i32::try_from(case_value).is_err() {
+                        return Err(format!("case value {case_value} does not fit in an i32"));
+                    }
+                    if !seen.insert(case_value) {
+                        return Err(format!("duplicate case value {case_value} in switch"));
+                    }
+                    let block = self.context.append_basic_block(func, "case");
+                    switch_cases.push((self.i32_type.const_int(case_value as u64, true), block));
+                    case_blocks.push(block);
+                }
+                None => {
+                    if default_block.is_some() {
+                        return Err("multiple default labels in one switch".to_string());
+                    }
+                    let block = self.context.append_basic_block(func, "default");
+                    default_block = Some(block);
+                    case_blocks.push(block);
+                }
+            }
+        }
+        let switch_end_block = self.context.append_basic_block(func, "switch_end");
+
+        self.builder.build_switch(
+            value,
+            default_block.unwrap_or(switch_end_block),
+            &switch_cases,
+        );
+
+        // The whole switch body is a single scope
+        let switch_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
+        self.breaks.borrow_mut().push(switch_end_block);
+        for (i, case) in cases.iter().enumerate() {
+            case_blocks[i]
+                .move_after(self.builder.get_insert_block().unwrap())
+                .unwrap();
+            self.builder.position_at_end(case_blocks[i]);
+            self.compile_stmts(&switch_env, &case.body)?;
+
+            let next_block = case_blocks.get(i + 1).copied();
+            self.branch_to(next_block.unwrap_or(switch_end_block));
+        }
+        self.breaks.borrow_mut().pop();
+
+        // Generate code for switch end block
+        switch_end_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(switch_end_block);
+        Ok(())
+    }
+}
+
 //
 // int main() {
 //     int a = 10;
 //     int b = 0;
-//     if (a > 0) {
-//        /* If block */
+//     if (a > 100) {
 //        b = 1;
-//     } else {
-//        /* Else block */
+//     } else if (a > 10) {
 //        b = 2;
+//     } else if (a > 0) {
+//        if (a == 10) {
+//           b = 3;
+//        } else {
+//           b = 4;
+//        }
+//     } else {
+//        b = 5;
 //     }
 //     /* Merge block */
 //     printf("%d\n", b);
 // }
+//
+// Prints 3
+//
 
-fn main() {
-    let context = Context::create();
-    let compiler = Compiler::new(&context);
+fn if_else_program() -> Vec<Stmt> {
+    vec![
+        declare("a", num(10)),
+        declare("b", num(0)),
+        Stmt::If {
+            branches: vec![
+                branch(
+                    binary(BinaryOp::Gt, var("a"), num(100)),
+                    vec![assign("b", num(1))],
+                ),
+                branch(
+                    binary(BinaryOp::Gt, var("a"), num(10)),
+                    vec![assign("b", num(2))],
+                ),
+                branch(
+                    binary(BinaryOp::Gt, var("a"), num(0)),
+                    vec![Stmt::If {
+                        branches: vec![branch(
+                            binary(BinaryOp::Eq, var("a"), num(10)),
+                            vec![assign("b", num(3))],
+                        )],
+                        else_body: Some(vec![assign("b", num(4))]),
+                    }],
+                ),
+            ],
+            else_body: Some(vec![assign("b", num(5))]),
+        },
+        print("%d\n", vec![var("b")]),
+    ]
+}
+
+//
+// int main() {
+//     int a = 2;
+//     int b = 0;
+//     switch (a) {
+//     case 1:
+//         b = b + 1;
+//     case 2:
+//         int c = 10;
+//         b = b + c;
+//     case 3:
+//         /* Falls through from case 2, c is still in scope */
+//         b = b + c * 10;
+//         break;
+//     default:
+//         return 1;
+//     }
+//     printf("%d\n", b);
+// }
+//
+// Prints 110
+//
+
+fn switch_program() -> Vec<Stmt> {
+    vec![
+        declare("a", num(2)),
+        declare("b", num(0)),
+        Stmt::Switch {
+            value: var("a"),
+            cases: vec![
+                case(
+                    1,
+                    vec![assign("b", binary(BinaryOp::Add, var("b"), num(1)))],
+                ),
+                case(
+                    2,
+                    vec![
+                        declare("c", num(10)),
+                        assign("b", binary(BinaryOp::Add, var("b"), var("c"))),
+                    ],
+                ),
+                case(
+                    3,
+                    vec![
+                        assign(
+                            "b",
+                            binary(
+                                BinaryOp::Add,
+                                var("b"),
+                                binary(BinaryOp::Mul, var("c"), num(10)),
+                            ),
+                        ),
+                        Stmt::Break,
+                    ],
+                ),
+                default(vec![Stmt::Return(num(1))]),
+            ],
+        },
+        print("%d\n", vec![var("b")]),
+    ]
+}
+
+//
+// int main() {
+//     int a = 10;
+//     int b = a > 0 ? 1 : 2;
+//     int c = a > 100 ? 3 : (a > 5 ? 4 : 5);
+//     int d = if (a > 5) {
+//         int e = a * 2;
+//         e + 1
+//     } else {
+//         0 - a
+//     };
+//     printf("%d %d %d\n", b, c, d);
+// }
+//
+// Prints 1 4 21, each conditional value comes out of a phi rather than a store
+//
+
+fn ternary_program() -> Vec<Stmt> {
+    vec![
+        declare("a", num(10)),
+        declare(
+            "b",
+            ternary(binary(BinaryOp::Gt, var("a"), num(0)), num(1), num(2)),
+        ),
+        declare(
+            "c",
+            ternary(
+                binary(BinaryOp::Gt, var("a"), num(100)),
+                num(3),
+                ternary(binary(BinaryOp::Gt, var("a"), num(5)), num(4), num(5)),
+            ),
+        ),
+        declare(
+            "d",
+            Expr::If {
+                cond: Box::new(binary(BinaryOp::Gt, var("a"), num(5))),
+                then_block: block_expr(
+                    vec![declare("e", binary(BinaryOp::Mul, var("a"), num(2)))],
+                    binary(BinaryOp::Add, var("e"), num(1)),
+                ),
+                else_block: block_expr(vec![], binary(BinaryOp::Sub, num(0), var("a"))),
+            },
+        ),
+        print("%d %d %d\n", vec![var("b"), var("c"), var("d")]),
+    ]
+}
+
+// main returns 0 unless the program returns first
+fn compile_program<'ctx>(context: &'ctx Context, stmts: &[Stmt]) -> Result<Compiler<'ctx>, String> {
+    let compiler = Compiler::new(context);
     let main_block = compiler
         .context
         .append_basic_block(compiler.main_func, "entry");
     compiler.builder.position_at_end(main_block);
 
-    let a_ptr = compiler.builder.build_alloca(compiler.i32_type, "a");
-    compiler
-        .builder
-        .build_store(a_ptr, compiler.i32_type.const_int(10, false));
+    let env = Rc::new(RefCell::new(Env::new(None)));
+    compiler.compile_stmts(&env, stmts)?;
 
-    let b_ptr = compiler.builder.build_alloca(compiler.i32_type, "b");
-    compiler
-        .builder
-        .build_store(b_ptr, compiler.i32_type.const_int(0, false));
-
-    let if_true_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "if_true");
+    if !compiler.is_terminated() {
+        let ret_val = compiler.i32_type.const_int(0, false);
+        compiler.builder.build_return(Some(&ret_val));
+    }
+    compiler.module.verify().unwrap();
+    Ok(compiler)
+}
 
-    let if_false_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "if_false");
+// Usage: cargo run --bin llvm-if-else [switch | ternary]
+fn main() {
+    let stmts = match std::env::args().nth(1).as_deref() {
+        Some("switch") => switch_program(),
+        Some("ternary") => ternary_program(),
+        _ => if_else_program(),
+    };
 
-    let merge_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "merge");
-
-    let lhs = compiler.builder.build_load(compiler.i32_type, a_ptr, "a");
-
-    let comparison = compiler.builder.build_int_compare(
-        inkwell::IntPredicate::SGT,
-        lhs.into_int_value(),
-        compiler.i32_type.const_int(0, false),
-        "a > 0",
-    );
-
-    compiler
-        .builder
-        .build_conditional_branch(comparison, if_true_block, if_false_block);
-
-    // Generate code for if true block
-    compiler.builder.position_at_end(if_true_block);
-    compiler
-        .builder
-        .build_store(b_ptr, compiler.i32_type.const_int(1, false));
-    compiler.builder.build_unconditional_branch(merge_block);
-    let then_block = compiler.builder.get_insert_block().unwrap();
-
-    // Generate code for if false block
-    compiler.builder.position_at_end(if_false_block);
-    compiler
-        .builder
-        .build_store(b_ptr, compiler.i32_type.const_int(2, false));
-    compiler.builder.build_unconditional_branch(merge_block);
-    let else_block = compiler.builder.get_insert_block().unwrap();
-
-    // Generate code for merge block
-    compiler.builder.position_at_end(merge_block);
-    let phi = compiler.builder.build_phi(compiler.i32_type, "phi");
-    phi.add_incoming(&[
-        (&compiler.i32_type.const_int(1, false), then_block),
-        (&compiler.i32_type.const_int(2, false), else_block),
-    ]);
-    compiler
-        .builder
-        .build_store(b_ptr, phi.as_basic_value().into_int_value());
-
-    let int_fmt_str = unsafe { compiler.builder.build_global_string("%d\n", "int_fmt_str") };
-
-    let b = compiler.builder.build_load(compiler.i32_type, b_ptr, "b");
-    compiler.builder.build_call(
-        compiler.printf_func,
-        &[int_fmt_str.as_pointer_value().into(), b.into()],
-        "printf",
-    );
-    let ret_val = compiler.i32_type.const_int(0, false);
-    compiler.builder.build_return(Some(&ret_val));
+    let context = Context::create();
+    let compiler = match compile_program(&context, &stmts) {
+        Ok(compiler) => compiler,
+        Err(e) => {
+            println!("Error: {e}");
+            return;
+        }
+    };
     compiler.module.print_to_file(Path::new("main.ll")).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use inkwell::targets::{InitializationConfig, Target};
+    use inkwell::OptimizationLevel;
+
+    fn run(stmts: Vec<Stmt>) -> i32 {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let context = Context::create();
+        let compiler = compile_program(&context, &stmts).unwrap();
+        let engine = compiler
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        unsafe {
+            let main = engine
+                .get_function::<unsafe extern "C" fn() -> i32>(MAIN_FUNC_NAME)
+                .unwrap();
+            main.call()
+        }
+    }
+
+    fn compile_error(stmts: Vec<Stmt>) -> String {
+        let context = Context::create();
+        match compile_program(&context, &stmts) {
+            Ok(_) => panic!("program compiled"),
+            Err(e) => e,
+        }
+    }
+
+    fn switch_on(value: i64, cases: Vec<Case>) -> Vec<Stmt> {
+        vec![
+            declare("b", num(0)),
+            Stmt::Switch {
+                value: num(value),
+                cases,
+            },
+            Stmt::Return(var("b")),
+        ]
+    }
+
+    #[test]
+    fn duplicate_case_values_are_rejected() {
+        let stmts = switch_on(1, vec![case(1, vec![]), case(2, vec![]), case(1, vec![])]);
+        assert_eq!(compile_error(stmts), "duplicate case value 1 in switch");
+    }
+
+    #[test]
+    fn a_second_default_is_rejected() {
+        let stmts = switch_on(1, vec![default(vec![]), case(1, vec![]), default(vec![])]);
+        assert_eq!(
+            compile_error(stmts),
+            "multiple default labels in one switch"
+        );
+    }
+
+    // 1 and 4294967297 would both become 1 once truncated to an i32
+    #[test]
+    fn case_values_outside_of_i32_are_rejected() {
+        let stmts = switch_on(1, vec![case(1, vec![]), case(4294967297, vec![])]);
+        assert_eq!(
+            compile_error(stmts),
+            "case value 4294967297 does not fit in an i32"
+        );
+    }
+
+    #[test]
+    fn cases_fall_through_until_a_break() {
+        let add = |n| assign("b", binary(BinaryOp::Add, var("b"), num(n)));
+        let cases = || {
+            vec![
+                case(1, vec![add(1)]),
+                case(2, vec![add(10)]),
+                case(3, vec![add(100), Stmt::Break]),
+                default(vec![add(1000)]),
+            ]
+        };
+        assert_eq!(run(switch_on(1, cases())), 111);
+        assert_eq!(run(switch_on(3, cases())), 100);
+        assert_eq!(run(switch_on(7, cases())), 1000);
+    }
+
+    #[test]
+    fn switch_program_falls_through_with_c_in_scope() {
+        let mut stmts = switch_program();
+        stmts.push(Stmt::Return(var("b")));
+        assert_eq!(run(stmts), 110);
+    }
+
+    fn block_names(stmts: &[Stmt]) -> Vec<String> {
+        let context = Context::create();
+        let compiler = compile_program(&context, stmts).unwrap();
+        compiler
+            .main_func
+            .get_basic_blocks()
+            .iter()
+            .map(|block| block.get_name().to_str().unwrap().to_string())
+            .collect()
+    }
+
+    //
+    // if (a > 100) { return 1; } else if (a > 10) { return 2; } else { return 3; }
+    //
+    fn returning_chain(a: i64) -> Vec<Stmt> {
+        vec![
+            declare("a", num(a)),
+            Stmt::If {
+                branches: vec![
+                    branch(
+                        binary(BinaryOp::Gt, var("a"), num(100)),
+                        vec![Stmt::Return(num(1))],
+                    ),
+                    branch(
+                        binary(BinaryOp::Gt, var("a"), num(10)),
+                        vec![Stmt::Return(num(2))],
+                    ),
+                ],
+                else_body: Some(vec![Stmt::Return(num(3))]),
+            },
+        ]
+    }
+
+    #[test]
+    fn a_chain_whose_arms_all_return_has_no_merge_block() {
+        assert_eq!(run(returning_chain(200)), 1);
+        assert_eq!(run(returning_chain(20)), 2);
+        assert_eq!(run(returning_chain(0)), 3);
+        assert!(!block_names(&returning_chain(0))
+            .iter()
+            .any(|name| name.starts_with("merge")));
+    }
+
+    #[test]
+    fn a_chain_that_falls_through_continues_in_one_merge_block() {
+        let mut stmts = if_else_program();
+        stmts.push(Stmt::Return(var("b")));
+        assert_eq!(run(stmts.clone()), 3);
+        let merges = block_names(&stmts)
+            .into_iter()
+            .filter(|name| name.starts_with("merge"))
+            .count();
+        // One for the outer chain and one for the if nested in its third arm
+        assert_eq!(merges, 2);
+    }
+
+    #[test]
+    fn an_if_without_else_falls_through_to_its_merge_block() {
+        let chain = |a| {
+            vec![
+                declare("b", num(0)),
+                Stmt::If {
+                    branches: vec![
+                        branch(
+                            binary(BinaryOp::Eq, num(a), num(1)),
+                            vec![Stmt::Return(num(10))],
+                        ),
+                        branch(
+                            binary(BinaryOp::Eq, num(a), num(2)),
+                            vec![assign("b", num(20))],
+                        ),
+                    ],
+                    else_body: None,
+                },
+                Stmt::Return(binary(BinaryOp::Add, var("b"), num(1))),
+            ]
+        };
+        assert_eq!(run(chain(1)), 10);
+        assert_eq!(run(chain(2)), 21);
+        assert_eq!(run(chain(3)), 1);
+    }
+
+    fn ternary_value(name: &str) -> i32 {
+        let mut stmts = ternary_program();
+        stmts.push(Stmt::Return(var(name)));
+        run(stmts)
+    }
+
+    #[test]
+    fn ternary_program_yields_1_4_21() {
+        assert_eq!(ternary_value("b"), 1);
+        assert_eq!(ternary_value("c"), 4);
+        assert_eq!(ternary_value("d"), 21);
+    }
+
+    //
+    // return a > 5 ? (a > 8 ? (a > 9 ? 1 : 2) : 3) : (if (a > 2) { a > 3 ? 4 : 5 } else { 6 });
+    //
+    // Every arm ends in a different block from the one it started in, so the
+    // phis are only valid if they take the builder's final block.
+    //
+    #[test]
+    fn nested_conditionals_merge_from_their_last_block() {
+        let gt = |n| binary(BinaryOp::Gt, var("a"), num(n));
+        let nested = |a| {
+            vec![
+                declare("a", num(a)),
+                Stmt::Return(ternary(
+                    gt(5),
+                    ternary(gt(8), ternary(gt(9), num(1), num(2)), num(3)),
+                    Expr::If {
+                        cond: Box::new(gt(2)),
+                        then_block: block_expr(vec![], ternary(gt(3), num(4), num(5))),
+                        else_block: block_expr(vec![], num(6)),
+                    },
+                )),
+            ]
+        };
+        let values: Vec<i32> = [10, 9, 6, 4, 3, 0]
+            .into_iter()
+            .map(|a| run(nested(a)))
+            .collect();
+        assert_eq!(values, vec![1, 2, 3, 4, 5, 6]);
+    }
+}
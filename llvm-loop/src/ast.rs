@@ -0,0 +1,72 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Lt,
+    Le,
+    Gt,
+    Eq,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i64),
+    Variable(String),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Declare(String, Expr),
+    Assign(String, Expr),
+    Print(String, Vec<Expr>),
+    If {
+        cond: Expr,
+        then_body: Vec<Stmt>,
+        else_body: Vec<Stmt>,
+    },
+    While {
+        cond: Expr,
+        body: Vec<Stmt>,
+    },
+    For {
+        init: Box<Stmt>,
+        cond: Expr,
+        step: Box<Stmt>,
+        body: Vec<Stmt>,
+    },
+    DoWhile {
+        body: Vec<Stmt>,
+        cond: Expr,
+    },
+    Loop(Vec<Stmt>),
+    Labeled(String, Box<Stmt>),
+    Break(Option<String>),
+    Continue(Option<String>),
+    Return(Expr),
+}
+
+pub fn num(value: i64) -> Expr {
+    Expr::Number(value)
+}
+
+pub fn var(name: &str) -> Expr {
+    Expr::Variable(name.to_string())
+}
+
+pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+}
+
+pub fn declare(name: &str, value: Expr) -> Stmt {
+    Stmt::Declare(name.to_string(), value)
+}
+
+pub fn assign(name: &str, value: Expr) -> Stmt {
+    Stmt::Assign(name.to_string(), value)
+}
+
+pub fn print(fmt: &str, args: Vec<Expr>) -> Stmt {
+    Stmt::Print(fmt.to_string(), args)
+}
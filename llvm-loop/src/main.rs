@@ -1,14 +1,29 @@
+mod ast;
+mod env;
+use crate::ast::*;
+use crate::env::*;
 use std::path::Path;
 
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::module::Module;
 use inkwell::types::IntType;
-use inkwell::values::FunctionValue;
+use inkwell::values::{BasicMetadataValueEnum, FunctionValue, IntValue, PointerValue};
 use inkwell::AddressSpace;
+use inkwell::IntPredicate;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 const MAIN_FUNC_NAME: &str = "main";
 
+// Branch targets of an enclosing loop
+pub struct LoopContext<'ctx> {
+    pub label: Option<String>,
+    pub continue_block: BasicBlock<'ctx>,
+    pub break_block: BasicBlock<'ctx>,
+}
+
 pub struct Compiler<'ctx> {
     pub context: &'ctx Context,
     pub builder: Builder<'ctx>,
@@ -17,6 +32,8 @@ pub struct Compiler<'ctx> {
 
     pub main_func: FunctionValue<'ctx>,
     pub printf_func: FunctionValue<'ctx>,
+
+    pub loops: RefCell<Vec<LoopContext<'ctx>>>,
 }
 
 impl<'ctx> Compiler<'ctx> {
@@ -38,8 +55,445 @@ impl<'ctx> Compiler<'ctx> {
             i32_type,
             main_func,
             printf_func,
+            loops: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+fn int_predicate(op: BinaryOp) -> Option<IntPredicate> {
+    match op {
+        BinaryOp::Lt => Some(IntPredicate::SLT),
+        BinaryOp::Le => Some(IntPredicate::SLE),
+        BinaryOp::Gt => Some(IntPredicate::SGT),
+        BinaryOp::Eq => Some(IntPredicate::EQ),
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => None,
+    }
+}
+
+impl<'ctx> Compiler<'ctx> {
+    fn current_function(&self) -> FunctionValue<'ctx> {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .unwrap()
+    }
+
+    //
+    // Every variable lives in the entry block, whichever loop declares it. An
+    // alloca in a loop body or for init runs on every iteration and keeps
+    // growing the stack.
+    //
+    fn build_entry_alloca(&self, ty: IntType<'ctx>, name: &str) -> PointerValue<'ctx> {
+        let entry_block = self.current_function().get_first_basic_block().unwrap();
+        let builder = self.context.create_builder();
+        match entry_block.get_first_instruction() {
+            Some(instruction) => builder.position_before(&instruction),
+            None => builder.position_at_end(entry_block),
+        }
+        builder.build_alloca(ty, name)
+    }
+
+    fn llvm_type(&self, data_type: &DataType) -> IntType<'ctx> {
+        match data_type {
+            DataType::Number => self.i32_type,
+        }
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|block| block.get_terminator())
+            .is_some()
+    }
+
+    // Fall through to the given block unless a break or continue already left
+    // the current one
+    fn branch_to(&self, block: BasicBlock<'ctx>) {
+        if !self.is_terminated() {
+            self.builder.build_unconditional_branch(block);
+        }
+    }
+
+    fn compile_loop_body(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        body: &[Stmt],
+        label: Option<&str>,
+        continue_block: BasicBlock<'ctx>,
+        break_block: BasicBlock<'ctx>,
+    ) -> Result<(), String> {
+        self.loops.borrow_mut().push(LoopContext {
+            label: label.map(|label| label.to_string()),
+            continue_block,
+            break_block,
+        });
+        let compiled = self.compile_block(env, body);
+        self.loops.borrow_mut().pop();
+        compiled
+    }
+
+    // An unlabeled break or continue targets the innermost loop, a labeled one
+    // the innermost loop carrying that label
+    fn find_loop(
+        &self,
+        keyword: &str,
+        label: &Option<String>,
+    ) -> Result<(BasicBlock<'ctx>, BasicBlock<'ctx>), String> {
+        let loops = self.loops.borrow();
+        let context = match label {
+            None => loops
+                .last()
+                .ok_or_else(|| format!("{keyword} outside of a loop"))?,
+            Some(label) => loops
+                .iter()
+                .rev()
+                .find(|context| context.label.as_ref() == Some(label))
+                .ok_or_else(|| format!("undefined loop label '{label}"))?,
+        };
+        Ok((context.continue_block, context.break_block))
+    }
+
+    fn compile_expr(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        expr: &Expr,
+    ) -> Result<IntValue<'ctx>, String> {
+        match expr {
+            Expr::Number(value) => Ok(self.i32_type.const_int(*value as u64, true)),
+            Expr::Variable(name) => {
+                let pointer = env
+                    .borrow()
+                    .get(name)
+                    .ok_or_else(|| format!("undefined variable {name}"))?;
+                Ok(self
+                    .builder
+                    .build_load(self.llvm_type(&pointer.data_type), pointer.ptr, name)
+                    .into_int_value())
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.compile_expr(env, lhs)?;
+                let rhs = self.compile_expr(env, rhs)?;
+                Ok(match op {
+                    BinaryOp::Add => self.builder.build_int_add(lhs, rhs, "add"),
+                    BinaryOp::Sub => self.builder.build_int_sub(lhs, rhs, "sub"),
+                    BinaryOp::Mul => self.builder.build_int_mul(lhs, rhs, "mul"),
+                    _ => {
+                        let predicate = int_predicate(*op).unwrap();
+                        let cmp = self.builder.build_int_compare(predicate, lhs, rhs, "cmp");
+                        self.builder.build_int_z_extend(cmp, self.i32_type, "cmp")
+                    }
+                })
+            }
+        }
+    }
+
+    // Comparisons are branched on directly, anything else is true when non-zero
+    fn compile_cond(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        expr: &Expr,
+    ) -> Result<IntValue<'ctx>, String> {
+        if let Expr::Binary(op, lhs, rhs) = expr {
+            if let Some(predicate) = int_predicate(*op) {
+                let lhs = self.compile_expr(env, lhs)?;
+                let rhs = self.compile_expr(env, rhs)?;
+                return Ok(self.builder.build_int_compare(predicate, lhs, rhs, "cond"));
+            }
+        }
+        let value = self.compile_expr(env, expr)?;
+        Ok(self.builder.build_int_compare(
+            IntPredicate::NE,
+            value,
+            self.i32_type.const_zero(),
+            "cond",
+        ))
+    }
+
+    // Every block gets its own Env so that declarations do not leak out of it.
+    // Statements following a break or continue are unreachable and skipped.
+    fn compile_block(&self, env: &Rc<RefCell<Env<'ctx>>>, stmts: &[Stmt]) -> Result<(), String> {
+        let block_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
+        for stmt in stmts {
+            if self.is_terminated() {
+                break;
+            }
+            self.compile_stmt(&block_env, stmt)?;
+        }
+        Ok(())
+    }
+
+    fn compile_stmt(&self, env: &Rc<RefCell<Env<'ctx>>>, stmt: &Stmt) -> Result<(), String> {
+        match stmt {
+            Stmt::Declare(name, value) => {
+                let value = self.compile_expr(env, value)?;
+                let ptr = self.build_entry_alloca(self.i32_type, name);
+                self.builder.build_store(ptr, value);
+                env.borrow_mut().add(
+                    name,
+                    Pointer {
+                        ptr,
+                        data_type: DataType::Number,
+                    },
+                );
+            }
+            Stmt::Assign(name, value) => {
+                let value = self.compile_expr(env, value)?;
+                let ptr = env
+                    .borrow()
+                    .get(name)
+                    .ok_or_else(|| format!("undefined variable {name}"))?
+                    .ptr;
+                self.builder.build_store(ptr, value);
+            }
+            Stmt::Print(fmt, args) => {
+                let fmt_str = unsafe { self.builder.build_global_string(fmt, "fmt_str") };
+                let mut call_args: Vec<BasicMetadataValueEnum> =
+                    vec![fmt_str.as_pointer_value().into()];
+                for arg in args {
+                    call_args.push(self.compile_expr(env, arg)?.into());
+                }
+                self.builder
+                    .build_call(self.printf_func, &call_args, "printf");
+            }
+            Stmt::If {
+                cond,
+                then_body,
+                else_body,
+            } => self.compile_if(env, cond, then_body, else_body)?,
+            Stmt::While { .. } | Stmt::For { .. } | Stmt::DoWhile { .. } | Stmt::Loop(_) => {
+                self.compile_loop(env, stmt, None)?
+            }
+            Stmt::Labeled(label, stmt) => self.compile_loop(env, stmt, Some(label.as_str()))?,
+            Stmt::Break(label) => {
+                let (_, break_block) = self.find_loop("break", label)?;
+                self.builder.build_unconditional_branch(break_block);
+            }
+            Stmt::Continue(label) => {
+                let (continue_block, _) = self.find_loop("continue", label)?;
+                self.builder.build_unconditional_branch(continue_block);
+            }
+            Stmt::Return(value) => {
+                let value = self.compile_expr(env, value)?;
+                self.builder.build_return(Some(&value));
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_loop(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        stmt: &Stmt,
+        label: Option<&str>,
+    ) -> Result<(), String> {
+        match stmt {
+            Stmt::While { cond, body } => self.compile_while(env, cond, body, label),
+            Stmt::For {
+                init,
+                cond,
+                step,
+                body,
+            } => self.compile_for(env, init, cond, step, body, label),
+            Stmt::DoWhile { body, cond } => self.compile_do_while(env, body, cond, label),
+            Stmt::Loop(body) => self.compile_infinite_loop(env, body, label),
+            _ => Err(format!(
+                "label '{} is not attached to a loop",
+                label.unwrap_or_default()
+            )),
         }
     }
+
+    fn compile_if(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        cond: &Expr,
+        then_body: &[Stmt],
+        else_body: &[Stmt],
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let if_true_block = self.context.append_basic_block(func, "if_true");
+        let if_false_block = self.context.append_basic_block(func, "if_false");
+        let merge_block = self.context.append_basic_block(func, "merge");
+
+        let comparison = self.compile_cond(env, cond)?;
+        self.builder
+            .build_conditional_branch(comparison, if_true_block, if_false_block);
+
+        // Generate code for if true block
+        self.builder.position_at_end(if_true_block);
+        self.compile_block(env, then_body)?;
+        self.branch_to(merge_block);
+
+        // Generate code for if false block
+        if_false_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(if_false_block);
+        self.compile_block(env, else_body)?;
+        self.branch_to(merge_block);
+
+        // Generate code for merge block
+        merge_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(merge_block);
+        Ok(())
+    }
+
+    fn compile_while(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        cond: &Expr,
+        body: &[Stmt],
+        label: Option<&str>,
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let cond_block = self.context.append_basic_block(func, "while.cond");
+        let body_block = self.context.append_basic_block(func, "while.body");
+        let loop_end_block = self.context.append_basic_block(func, "while.end");
+
+        self.builder.build_unconditional_branch(cond_block);
+
+        self.builder.position_at_end(cond_block);
+        let comparison = self.compile_cond(env, cond)?;
+        self.builder
+            .build_conditional_branch(comparison, body_block, loop_end_block);
+
+        // Generate code for body block
+        self.builder.position_at_end(body_block);
+        self.compile_loop_body(env, body, label, cond_block, loop_end_block)?;
+        self.branch_to(cond_block);
+
+        // Generate code for loop end block, after any blocks the body added
+        loop_end_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(loop_end_block);
+        Ok(())
+    }
+
+    //
+    // for (init; cond; step) { body }
+    //
+    // init runs once in the current block, cond is re-evaluated in its own
+    // block, and step gets a dedicated block that the body falls through to.
+    //
+    fn compile_for(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        init: &Stmt,
+        cond: &Expr,
+        step: &Stmt,
+        body: &[Stmt],
+        label: Option<&str>,
+    ) -> Result<(), String> {
+        // The init variable is only visible to cond, step and body
+        let for_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
+        self.compile_stmt(&for_env, init)?;
+
+        let func = self.current_function();
+        let cond_block = self.context.append_basic_block(func, "for.cond");
+        let body_block = self.context.append_basic_block(func, "for.body");
+        let step_block = self.context.append_basic_block(func, "for.step");
+        let loop_end_block = self.context.append_basic_block(func, "for.end");
+
+        self.builder.build_unconditional_branch(cond_block);
+
+        self.builder.position_at_end(cond_block);
+        let comparison = self.compile_cond(&for_env, cond)?;
+        self.builder
+            .build_conditional_branch(comparison, body_block, loop_end_block);
+
+        // Generate code for body block, continue runs the step before the
+        // condition is checked again
+        self.builder.position_at_end(body_block);
+        self.compile_loop_body(&for_env, body, label, step_block, loop_end_block)?;
+        self.branch_to(step_block);
+
+        // Generate code for step block
+        step_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(step_block);
+        self.compile_stmt(&for_env, step)?;
+        self.builder.build_unconditional_branch(cond_block);
+
+        // Generate code for loop end block
+        loop_end_block.move_after(step_block).unwrap();
+        self.builder.position_at_end(loop_end_block);
+        Ok(())
+    }
+
+    //
+    // do { body } while (cond);
+    //
+    // The body is entered unconditionally, continue jumps to the condition.
+    //
+    fn compile_do_while(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        body: &[Stmt],
+        cond: &Expr,
+        label: Option<&str>,
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let body_block = self.context.append_basic_block(func, "do.body");
+        let cond_block = self.context.append_basic_block(func, "do.cond");
+        let loop_end_block = self.context.append_basic_block(func, "do.end");
+
+        self.builder.build_unconditional_branch(body_block);
+
+        // Generate code for body block
+        self.builder.position_at_end(body_block);
+        self.compile_loop_body(env, body, label, cond_block, loop_end_block)?;
+        self.branch_to(cond_block);
+
+        // Generate code for cond block
+        cond_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(cond_block);
+        let comparison = self.compile_cond(env, cond)?;
+        self.builder
+            .build_conditional_branch(comparison, body_block, loop_end_block);
+
+        // Generate code for loop end block
+        loop_end_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(loop_end_block);
+        Ok(())
+    }
+
+    //
+    // loop { body }
+    //
+    // There is no condition block, the loop end is only reachable via break.
+    //
+    fn compile_infinite_loop(
+        &self,
+        env: &Rc<RefCell<Env<'ctx>>>,
+        body: &[Stmt],
+        label: Option<&str>,
+    ) -> Result<(), String> {
+        let func = self.current_function();
+        let body_block = self.context.append_basic_block(func, "loop.body");
+        let loop_end_block = self.context.append_basic_block(func, "loop.end");
+
+        self.builder.build_unconditional_branch(body_block);
+
+        // Generate code for body block
+        self.builder.position_at_end(body_block);
+        self.compile_loop_body(env, body, label, body_block, loop_end_block)?;
+        self.branch_to(body_block);
+
+        // Generate code for loop end block
+        loop_end_block
+            .move_after(self.builder.get_insert_block().unwrap())
+            .unwrap();
+        self.builder.position_at_end(loop_end_block);
+        Ok(())
+    }
 }
 
 //
@@ -49,71 +503,452 @@ impl<'ctx> Compiler<'ctx> {
 //     a = a - 1;
 //     printf("%d\n", a);
 //   }
+//
+//   for (int i = 1; i <= 9; i = i + 1) {
+//     for (int j = 1; j <= 9; j = j + 1) {
+//       printf("%d x %d = %d\n", i, j, i * j);
+//     }
+//   }
+//
+//   int n = 0;
+//   while (n < 100) {
+//     n = n + 1;
+//     if (n < 5) {
+//       continue;
+//     }
+//     for (int k = 0; k < 10; k = k + 1) {
+//       if (k == 2) {
+//         break;
+//       }
+//       printf("n = %d, k = %d\n", n, k);
+//     }
+//     if (n == 6) {
+//       break;
+//       n = 100; /* unreachable */
+//     }
+//   }
 //   return 0;
 // }
 
-fn main() {
-    let context = Context::create();
-    let compiler = Compiler::new(&context);
+fn program() -> Vec<Stmt> {
+    vec![
+        declare("a", num(10)),
+        Stmt::While {
+            cond: binary(BinaryOp::Gt, var("a"), num(0)),
+            body: vec![
+                assign("a", binary(BinaryOp::Sub, var("a"), num(1))),
+                print("%d\n", vec![var("a")]),
+            ],
+        },
+        Stmt::For {
+            init: Box::new(declare("i", num(1))),
+            cond: binary(BinaryOp::Le, var("i"), num(9)),
+            step: Box::new(assign("i", binary(BinaryOp::Add, var("i"), num(1)))),
+            body: vec![Stmt::For {
+                init: Box::new(declare("j", num(1))),
+                cond: binary(BinaryOp::Le, var("j"), num(9)),
+                step: Box::new(assign("j", binary(BinaryOp::Add, var("j"), num(1)))),
+                body: vec![print(
+                    "%d x %d = %d\n",
+                    vec![
+                        var("i"),
+                        var("j"),
+                        binary(BinaryOp::Mul, var("i"), var("j")),
+                    ],
+                )],
+            }],
+        },
+        declare("n", num(0)),
+        Stmt::While {
+            cond: binary(BinaryOp::Lt, var("n"), num(100)),
+            body: vec![
+                assign("n", binary(BinaryOp::Add, var("n"), num(1))),
+                Stmt::If {
+                    cond: binary(BinaryOp::Lt, var("n"), num(5)),
+                    then_body: vec![Stmt::Continue(None)],
+                    else_body: vec![],
+                },
+                Stmt::For {
+                    init: Box::new(declare("k", num(0))),
+                    cond: binary(BinaryOp::Lt, var("k"), num(10)),
+                    step: Box::new(assign("k", binary(BinaryOp::Add, var("k"), num(1)))),
+                    body: vec![
+                        Stmt::If {
+                            cond: binary(BinaryOp::Eq, var("k"), num(2)),
+                            then_body: vec![Stmt::Break(None)],
+                            else_body: vec![],
+                        },
+                        print("n = %d, k = %d\n", vec![var("n"), var("k")]),
+                    ],
+                },
+                Stmt::If {
+                    cond: binary(BinaryOp::Eq, var("n"), num(6)),
+                    then_body: vec![Stmt::Break(None), assign("n", num(100))],
+                    else_body: vec![],
+                },
+            ],
+        },
+    ]
+}
+
+//
+// int main() {
+//   int count = 0;
+//   do {
+//     count = count + 1;
+//   } while (count < 0);
+//
+//   do {
+//     count = count + 1;
+//   } while (count < 10);
+//   return count;
+// }
+//
+// Exits with 10, the first body runs once even though its condition is false
+//
+
+fn do_while_program() -> Vec<Stmt> {
+    vec![
+        declare("count", num(0)),
+        Stmt::DoWhile {
+            body: vec![assign("count", binary(BinaryOp::Add, var("count"), num(1)))],
+            cond: binary(BinaryOp::Lt, var("count"), num(0)),
+        },
+        Stmt::DoWhile {
+            body: vec![assign("count", binary(BinaryOp::Add, var("count"), num(1)))],
+            cond: binary(BinaryOp::Lt, var("count"), num(10)),
+        },
+        Stmt::Return(var("count")),
+    ]
+}
+
+//
+// int main() {
+//   int count = 0;
+//   loop {
+//     count = count + 1;
+//     if (count == 42) {
+//       break;
+//     }
+//   }
+//   return count;
+// }
+//
+// Exits with 42
+//
+
+fn infinite_loop_program() -> Vec<Stmt> {
+    vec![
+        declare("count", num(0)),
+        Stmt::Loop(vec![
+            assign("count", binary(BinaryOp::Add, var("count"), num(1))),
+            Stmt::If {
+                cond: binary(BinaryOp::Eq, var("count"), num(42)),
+                then_body: vec![Stmt::Break(None)],
+                else_body: vec![],
+            },
+        ]),
+        Stmt::Return(var("count")),
+    ]
+}
+
+//
+// int main() {
+//   int count = 0;
+//   'outer: for (int i = 0; i < 10; i = i + 1) {
+//     for (int j = 0; j < 10; j = j + 1) {
+//       loop {
+//         count = count + 1;
+//         if (j == 3) {
+//           continue 'outer;
+//         }
+//         if (i == 4) {
+//           break 'outer;
+//         }
+//         break;
+//       }
+//     }
+//   }
+//   return count;
+// }
+//
+// Exits with 17, four iterations of j for each i below 4 and one for i == 4
+//
+
+fn labeled_program() -> Vec<Stmt> {
+    vec![
+        declare("count", num(0)),
+        Stmt::Labeled(
+            "outer".to_string(),
+            Box::new(Stmt::For {
+                init: Box::new(declare("i", num(0))),
+                cond: binary(BinaryOp::Lt, var("i"), num(10)),
+                step: Box::new(assign("i", binary(BinaryOp::Add, var("i"), num(1)))),
+                body: vec![Stmt::For {
+                    init: Box::new(declare("j", num(0))),
+                    cond: binary(BinaryOp::Lt, var("j"), num(10)),
+                    step: Box::new(assign("j", binary(BinaryOp::Add, var("j"), num(1)))),
+                    body: vec![Stmt::Loop(vec![
+                        assign("count", binary(BinaryOp::Add, var("count"), num(1))),
+                        Stmt::If {
+                            cond: binary(BinaryOp::Eq, var("j"), num(3)),
+                            then_body: vec![Stmt::Continue(Some("outer".to_string()))],
+                            else_body: vec![],
+                        },
+                        Stmt::If {
+                            cond: binary(BinaryOp::Eq, var("i"), num(4)),
+                            then_body: vec![Stmt::Break(Some("outer".to_string()))],
+                            else_body: vec![],
+                        },
+                        Stmt::Break(None),
+                    ])],
+                }],
+            }),
+        ),
+        Stmt::Return(var("count")),
+    ]
+}
+
+// Compile the statements as the body of main, returning 0 when the last block
+// falls through. Statements following a top-level return are unreachable and
+// skipped.
+fn compile_program<'ctx>(context: &'ctx Context, stmts: &[Stmt]) -> Result<Compiler<'ctx>, String> {
+    let compiler = Compiler::new(context);
     let main_block = compiler
         .context
         .append_basic_block(compiler.main_func, "entry");
     compiler.builder.position_at_end(main_block);
 
-    let ptr = compiler.builder.build_alloca(compiler.i32_type, "a");
-    compiler
-        .builder
-        .build_store(ptr, compiler.i32_type.const_int(10, false));
+    let env = Rc::new(RefCell::new(Env::new(None)));
+    for stmt in stmts {
+        if compiler.is_terminated() {
+            break;
+        }
+        compiler.compile_stmt(&env, stmt)?;
+    }
 
-    let cond_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "cond");
-    let body_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "body");
-    let loop_end_block = compiler
-        .context
-        .append_basic_block(compiler.main_func, "loop_end");
-
-    compiler.builder.build_unconditional_branch(cond_block);
-
-    compiler.builder.position_at_end(cond_block);
-    let lhs = compiler.builder.build_load(compiler.i32_type, ptr, "a");
-    let comparison = compiler.builder.build_int_compare(
-        inkwell::IntPredicate::SGT,
-        lhs.into_int_value(),
-        compiler.i32_type.const_int(0, false),
-        "a > 0",
-    );
-
-    compiler
-        .builder
-        .build_conditional_branch(comparison, body_block, loop_end_block);
-
-    // Generate code for body block
-    compiler.builder.position_at_end(body_block);
-    let lhs = compiler.builder.build_load(compiler.i32_type, ptr, "a");
-    let new_val = compiler.builder.build_int_sub(
-        lhs.into_int_value(),
-        compiler.i32_type.const_int(1, false),
-        "a - 1",
-    );
-    compiler.builder.build_store(ptr, new_val);
-
-    let int_fmt_str = unsafe { compiler.builder.build_global_string("%d\n", "int_fmt_str") };
-    let a = compiler.builder.build_load(compiler.i32_type, ptr, "a");
-    compiler.builder.build_call(
-        compiler.printf_func,
-        &[int_fmt_str.as_pointer_value().into(), a.into()],
-        "printf",
-    );
-
-    compiler.builder.build_unconditional_branch(cond_block);
-
-    // Generate code for merge block
-    compiler.builder.position_at_end(loop_end_block);
-
-    let ret_val = compiler.i32_type.const_int(0, false);
-    compiler.builder.build_return(Some(&ret_val));
+    if !compiler.is_terminated() {
+        let ret_val = compiler.i32_type.const_int(0, false);
+        compiler.builder.build_return(Some(&ret_val));
+    }
+    compiler.module.verify().unwrap();
+    Ok(compiler)
+}
+
+// Usage: cargo run --bin llvm-loop [do_while | loop | labeled]
+fn main() {
+    let stmts = match std::env::args().nth(1).as_deref() {
+        Some("do_while") => do_while_program(),
+        Some("loop") => infinite_loop_program(),
+        Some("labeled") => labeled_program(),
+        _ => program(),
+    };
+
+    let context = Context::create();
+    let compiler = match compile_program(&context, &stmts) {
+        Ok(compiler) => compiler,
+        Err(e) => {
+            println!("Error: {e}");
+            return;
+        }
+    };
     compiler.module.print_to_file(Path::new("main.ll")).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use inkwell::targets::{InitializationConfig, Target};
+    use inkwell::values::InstructionOpcode;
+    use inkwell::OptimizationLevel;
+
+    fn run(stmts: Vec<Stmt>) -> i32 {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let context = Context::create();
+        let compiler = compile_program(&context, &stmts).unwrap();
+        let engine = compiler
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        unsafe {
+            let main = engine
+                .get_function::<unsafe extern "C" fn() -> i32>(MAIN_FUNC_NAME)
+                .unwrap();
+            main.call()
+        }
+    }
+
+    fn compile_error(stmts: Vec<Stmt>) -> String {
+        let context = Context::create();
+        match compile_program(&context, &stmts) {
+            Ok(_) => panic!("compiled: {stmts:?}"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn break_outside_of_a_loop() {
+        assert_eq!(
+            compile_error(vec![Stmt::Break(None)]),
+            "break outside of a loop"
+        );
+    }
+
+    #[test]
+    fn continue_outside_of_a_loop() {
+        assert_eq!(
+            compile_error(vec![Stmt::Continue(None)]),
+            "continue outside of a loop"
+        );
+    }
+
+    #[test]
+    fn undefined_loop_label() {
+        let stmts = vec![Stmt::Loop(vec![Stmt::Break(Some("outer".to_string()))])];
+        assert_eq!(compile_error(stmts), "undefined loop label 'outer");
+    }
+
+    #[test]
+    fn label_on_a_statement_that_is_not_a_loop() {
+        let stmts = vec![Stmt::Labeled(
+            "outer".to_string(),
+            Box::new(declare("a", num(1))),
+        )];
+        assert_eq!(
+            compile_error(stmts),
+            "label 'outer is not attached to a loop"
+        );
+    }
+
+    #[test]
+    fn labeled_exits() {
+        assert_eq!(run(labeled_program()), 17);
+    }
+
+    #[test]
+    fn do_while_runs_body_first() {
+        assert_eq!(run(do_while_program()), 10);
+    }
+
+    #[test]
+    fn infinite_loop_breaks() {
+        assert_eq!(run(infinite_loop_program()), 42);
+    }
+
+    #[test]
+    fn nested_loops_return_zero() {
+        assert_eq!(run(program()), 0);
+    }
+
+    //
+    // int main() {
+    //   int total = 0;
+    //   for (int i = 0; i < 1000000; i = i + 1) {
+    //     int step = 1;
+    //     total = total + step;
+    //   }
+    //   return total == 1000000;
+    // }
+    //
+    #[test]
+    fn loop_locals_are_allocated_in_the_entry_block() {
+        let stmts = vec![
+            declare("total", num(0)),
+            Stmt::For {
+                init: Box::new(declare("i", num(0))),
+                cond: binary(BinaryOp::Lt, var("i"), num(1000000)),
+                step: Box::new(assign("i", binary(BinaryOp::Add, var("i"), num(1)))),
+                body: vec![
+                    declare("step", num(1)),
+                    assign("total", binary(BinaryOp::Add, var("total"), var("step"))),
+                ],
+            },
+            Stmt::Return(binary(BinaryOp::Eq, var("total"), num(1000000))),
+        ];
+        let context = Context::create();
+        let compiler = compile_program(&context, &stmts).unwrap();
+        for block in compiler.main_func.get_basic_blocks().into_iter().skip(1) {
+            let mut instruction = block.get_first_instruction();
+            while let Some(current) = instruction {
+                assert_ne!(current.get_opcode(), InstructionOpcode::Alloca);
+                instruction = current.get_next_instruction();
+            }
+        }
+        assert_eq!(run(stmts), 1);
+    }
+
+    #[test]
+    fn statements_after_a_top_level_return_are_skipped() {
+        let stmts = vec![Stmt::Return(num(7)), print("unreachable\n", vec![])];
+        assert_eq!(run(stmts), 7);
+    }
+
+    // Names of the blocks of main, without the suffix LLVM adds to repeated
+    // names
+    fn block_names(stmts: Vec<Stmt>) -> Vec<String> {
+        let context = Context::create();
+        let compiler = compile_program(&context, &stmts).unwrap();
+        compiler
+            .main_func
+            .get_basic_blocks()
+            .iter()
+            .map(|block| {
+                let name = block.get_name().to_str().unwrap();
+                name.trim_end_matches(|c: char| c.is_ascii_digit())
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn for_loops_get_named_blocks() {
+        let stmts = vec![Stmt::For {
+            init: Box::new(declare("i", num(0))),
+            cond: binary(BinaryOp::Lt, var("i"), num(3)),
+            step: Box::new(assign("i", binary(BinaryOp::Add, var("i"), num(1)))),
+            body: vec![Stmt::For {
+                init: Box::new(declare("j", num(0))),
+                cond: binary(BinaryOp::Lt, var("j"), num(3)),
+                step: Box::new(assign("j", binary(BinaryOp::Add, var("j"), num(1)))),
+                body: vec![],
+            }],
+        }];
+        assert_eq!(
+            block_names(stmts),
+            [
+                "entry", "for.cond", "for.body", "for.cond", "for.body", "for.step", "for.end",
+                "for.step", "for.end",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_loop_form_gets_named_blocks() {
+        assert_eq!(
+            block_names(do_while_program()),
+            ["entry", "do.body", "do.cond", "do.end", "do.body", "do.cond", "do.end"]
+        );
+        assert_eq!(
+            block_names(infinite_loop_program()),
+            [
+                "entry",
+                "loop.body",
+                "if_true",
+                "if_false",
+                "merge",
+                "loop.end"
+            ]
+        );
+        let stmts = vec![
+            declare("a", num(3)),
+            Stmt::While {
+                cond: binary(BinaryOp::Gt, var("a"), num(0)),
+                body: vec![assign("a", binary(BinaryOp::Sub, var("a"), num(1)))],
+            },
+        ];
+        assert_eq!(
+            block_names(stmts),
+            ["entry", "while.cond", "while.body", "while.end"]
+        );
+    }
+}